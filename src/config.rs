@@ -1,9 +1,13 @@
+use std::fmt;
+use std::fmt::Formatter;
 use std::fs;
 use log::LevelFilter;
 use serde::Deserialize;
 use anyhow::Result;
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, NaiveTime, Timelike};
+use chrono_tz::Tz;
 use thiserror::Error;
+use crate::worker_common::{BlockType, BLOCK_UNIT_SIZE};
 
 #[derive(Deserialize)]
 pub struct FoxESS {
@@ -13,6 +17,34 @@ pub struct FoxESS {
     pub inverter_sn: String,
 }
 
+/// Available inverter backends
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InverterBackend {
+    FoxEss,
+    Simulator,
+}
+
+#[derive(Deserialize)]
+pub struct InverterParameters {
+    pub backend: InverterBackend,
+    pub max_segment_count: usize,
+}
+
+#[derive(Deserialize)]
+pub struct Battery {
+    pub capacity_kwh: f64,
+    pub charge_power_kw: f64,
+    pub min_soc: usize,
+    pub max_soc: usize,
+}
+
+#[derive(Deserialize)]
+pub struct SimulatorParameters {
+    pub initial_soc: u8,
+    pub load_kw: f64,
+}
+
 #[derive(Deserialize)]
 pub struct MailParameters {
     #[serde(default)]
@@ -24,26 +56,97 @@ pub struct MailParameters {
     pub to: String,
 }
 
+/// How to choose among dated schedule files overlapping in time
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulePrecedence {
+    LatestStart,
+    HighestId,
+    NewestMtime,
+}
+
+/// Implementation of the Display Trait for pretty print
+impl fmt::Display for SchedulePrecedence {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SchedulePrecedence::LatestStart => write!(f, "latest start"),
+            SchedulePrecedence::HighestId   => write!(f, "highest schedule id"),
+            SchedulePrecedence::NewestMtime => write!(f, "newest modification time"),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Files {
     pub schedule_dir: String,
     pub manual_file: String,
+    pub poll_interval_seconds: i64,
+    pub schedule_precedence: SchedulePrecedence,
 }
 
 #[derive(Deserialize)]
 pub struct General {
     pub debug_run_time: Option<DateTime<Local>>,
+    pub debug_speed_factor: Option<f64>,
+    pub timezone: Tz,
     pub log_path: String,
     pub log_level: LevelFilter,
     pub log_to_stdout: bool,
     pub debug_mode: bool,
 }
 
+#[derive(Deserialize)]
+pub struct WorkerParameters {
+    pub mode_mismatch_grace_minutes: i64,
+    pub reconcile_interval_minutes: i64,
+    pub lookahead_hour: u32,
+    pub soc_deviation_threshold: i64,
+}
+
+#[derive(Deserialize)]
+pub struct PricePlanner {
+    pub dir: String,
+}
+
+#[derive(Deserialize)]
+pub struct History {
+    pub dir: String,
+    pub retention_days: u64,
+}
+
+/// A block of the fallback schedule given in local time. The end is exclusive and 00:00 means end of day.
+#[derive(Deserialize, Clone)]
+pub struct FallbackBlock {
+    pub block_type: BlockType,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub soc_in: usize,
+    pub soc_out: usize,
+    pub min_soc_on_grid: Option<f64>,
+    pub fd_pwr: Option<f64>,
+    pub import_limit: Option<f64>,
+    pub export_limit: Option<f64>,
+    pub pv_limit: Option<f64>,
+}
+
+/// The typical day used when no valid schedule exists
+#[derive(Deserialize, Clone)]
+pub struct Fallback {
+    pub blocks: Vec<FallbackBlock>,
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     pub fox_ess: FoxESS,
+    pub inverter: InverterParameters,
+    pub battery: Battery,
+    pub simulator: Option<SimulatorParameters>,
     pub mail: MailParameters,
     pub files: Files,
+    pub history: History,
+    pub worker: WorkerParameters,
+    pub fallback: Fallback,
+    pub price_planner: PricePlanner,
     pub general: General,
 }
 
@@ -56,14 +159,57 @@ pub fn load_config(config_path: &str) -> Result<Config, ConfigError> {
     
     let toml = fs::read_to_string(config_path)?;
     let config: Config = toml::from_str(&toml)?;
-    
+    validate_fallback(&config.fallback)?;
+    if config.inverter.backend == InverterBackend::Simulator && config.simulator.is_none() {
+        return Err(ConfigError::Simulator("backend simulator requires a [simulator] section".to_string()));
+    }
+
     Ok(config)
 }
 
+/// Checks that the fallback blocks are aligned to the block unit size, in order and not overlapping,
+/// and that Discharge blocks have a discharge power
+///
+/// # Arguments
+///
+/// * 'fallback' - the fallback schedule to check
+fn validate_fallback(fallback: &Fallback) -> Result<(), ConfigError> {
+    let minute_of_day = |t: NaiveTime| (t.hour() * 60 + t.minute()) as i64;
+    let mut previous_end = 0;
+
+    for fb in &fallback.blocks {
+        let start = minute_of_day(fb.start);
+        let end = if fb.end == NaiveTime::MIN { 24 * 60 } else { minute_of_day(fb.end) };
+
+        if fb.block_type == BlockType::Unknown {
+            return Err(ConfigError::Fallback("block type Unknown is not allowed".to_string()));
+        }
+        if fb.start.second() != 0 || fb.end.second() != 0 || start % BLOCK_UNIT_SIZE != 0 || end % BLOCK_UNIT_SIZE != 0 {
+            return Err(ConfigError::Fallback(format!("block {}-{} is not aligned to {} minutes", fb.start, fb.end, BLOCK_UNIT_SIZE)));
+        }
+        if fb.block_type == BlockType::Discharge && fb.fd_pwr.is_none_or(|p| p <= 0.0) {
+            return Err(ConfigError::Fallback(format!("Discharge block {}-{} needs a positive fd_pwr", fb.start, fb.end)));
+        }
+        if end <= start {
+            return Err(ConfigError::Fallback(format!("block {}-{} ends before it starts", fb.start, fb.end)));
+        }
+        if start < previous_end {
+            return Err(ConfigError::Fallback(format!("block {}-{} overlaps or is out of order", fb.start, fb.end)));
+        }
+        previous_end = end;
+    }
+
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("error while loading configuration: {0}")]
     IoError(#[from] std::io::Error),
     #[error("error while parsing configuration: {0}")]
-    TomlError(#[from] toml::de::Error),    
+    TomlError(#[from] toml::de::Error),
+    #[error("error in fallback schedule: {0}")]
+    Fallback(String),
+    #[error("error in simulator configuration: {0}")]
+    Simulator(String),
 }
@@ -0,0 +1,109 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use log::{info, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::UtcNow;
+use crate::manager_files::is_own_schedule_write;
+
+/// Time without further events before a change is considered complete
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// Watches the schedule directory and the manual file for changes using inotify.
+///
+/// If the watch can't be set up the watcher degrades to a plain sleep, in which case
+/// the caller's polling is the only way changes are detected.
+pub struct FileWatcher {
+    watcher: Option<RecommendedWatcher>,
+    rx: Receiver<notify::Result<Event>>,
+    manual_file: PathBuf,
+}
+
+impl FileWatcher {
+    /// Returns a new instance of the FileWatcher struct
+    ///
+    /// # Arguments
+    ///
+    /// * 'schedule_dir' - directory holding schedule files
+    /// * 'manual_file' - the file holding manual dates
+    pub fn new(schedule_dir: &str, manual_file: &str) -> Self {
+        let (tx, rx) = channel();
+        let manual_file = PathBuf::from(manual_file);
+
+        // The manual file's directory is watched rather than the file itself since editors tend to replace files
+        let manual_dir = manual_file.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+        let watcher = notify::recommended_watcher(tx)
+            .and_then(|mut w| {
+                w.watch(Path::new(schedule_dir), RecursiveMode::NonRecursive)?;
+                w.watch(manual_dir, RecursiveMode::NonRecursive)?;
+                Ok(w)
+            });
+
+        let watcher = match watcher {
+            Ok(w) => {
+                info!("watching {} and {} for changes", schedule_dir, manual_dir.display());
+                Some(w)
+            },
+            Err(e) => {
+                warn!("failed to watch files, falling back to polling only: {}", e);
+                None
+            },
+        };
+
+        Self { watcher, rx, manual_file }
+    }
+
+    /// Waits for a relevant file change or until the timeout has passed, whichever comes first.
+    /// Returns true if any schedule file or the manual file has changed.
+    ///
+    /// # Arguments
+    ///
+    /// * 'time' - the worker's clock, the timeout is given in worker's clock time
+    /// * 'timeout' - maximum time to wait
+    pub fn wait_for_change(&self, time: &UtcNow, timeout: Duration) -> bool {
+        if self.watcher.is_none() {
+            time.sleep(timeout);
+            return false;
+        }
+
+        let mut changed = match self.rx.recv_timeout(time.real_duration(timeout)) {
+            Ok(event) => self.is_relevant(event),
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => {
+                time.sleep(timeout);
+                false
+            },
+        };
+
+        // Let a burst of writes settle so that a file isn't read while only half written
+        while let Ok(event) = self.rx.recv_timeout(SETTLE_TIME) {
+            changed |= self.is_relevant(event);
+        }
+
+        changed
+    }
+
+    /// Checks whether an event concerns a schedule file or the manual file.
+    /// Events caused by mygrid saving schedule.json itself are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * 'event' - the event to check
+    fn is_relevant(&self, event: notify::Result<Event>) -> bool {
+        match event {
+            Ok(event) => {
+                !matches!(event.kind, EventKind::Access(_)) && event.paths.iter().any(|p| {
+                    let schedule_file = p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.ends_with("schedule.json"));
+                    (schedule_file && !(p.file_name() == Some(OsStr::new("schedule.json")) && is_own_schedule_write(p)))
+                        || p.file_name() == self.manual_file.file_name()
+                })
+            },
+            Err(e) => {
+                warn!("file watch error: {}", e);
+                false
+            },
+        }
+    }
+}
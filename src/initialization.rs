@@ -4,13 +4,15 @@ use log::info;
 use foxess::{Fox, FoxError};
 use thiserror::Error;
 use crate::{UtcNow, DEBUG_MODE, LOGGER_INITIALIZED};
-use crate::config::{load_config, Config, ConfigError};
+use crate::config::{load_config, Config, ConfigError, InverterBackend};
 use crate::logging::{setup_logger, LoggingError};
+use crate::manager_inverter::Inverter;
+use crate::manager_inverter::simulator::Simulator;
 use crate::manager_mail::errors::MailError;
 use crate::manager_mail::Mail;
 
 pub struct Mgr {
-    pub fox: Fox,
+    pub inverter: Box<dyn Inverter>,
     pub mail: Mail,
     pub time: UtcNow,
 }
@@ -19,48 +21,56 @@ pub struct Mgr {
 /// an optional LastCharge struct, and an optional active block
 ///
 pub fn init() -> Result<(Config, Mgr), MyGridInitError> {
-    let args: Vec<String> = env::args().collect();
-    let config_path = args.iter()
-        .find(|p| p.starts_with("--config="))
-        .expect("config file argument should be present");
-    let config_path = config_path
-        .split_once('=')
-        .expect("config file argument should be correct")
-        .1;
-
-
     // Load configuration
-    let mut config = load_config(&config_path)?;
-    config.fox_ess.api_key = read_credential("fox_ess_api_key")?;
-    config.fox_ess.inverter_sn = read_credential("fox_ess_inverter_sn")?;
-    config.mail.smtp_user = read_credential("mail_smtp_user")?;
-    config.mail.smtp_password = read_credential("mail_smtp_password")?;
+    let mut config = load_config(&get_config_path())?;
+    // The simulator runs offline, it needs neither inverter nor mail credentials
+    if config.inverter.backend == InverterBackend::FoxEss {
+        config.fox_ess.api_key = read_credential("fox_ess_api_key")?;
+        config.fox_ess.inverter_sn = read_credential("fox_ess_inverter_sn")?;
+        config.mail.smtp_user = read_credential("mail_smtp_user")?;
+        config.mail.smtp_password = read_credential("mail_smtp_password")?;
+    }
 
     // Setup logging
     if !*LOGGER_INITIALIZED.read().map_err(|e| MyGridInitError::LockPoisonRead(e.to_string()))? {
-        let _ = setup_logger(&config.general.log_path, config.general.log_level, config.general.log_to_stdout)?;
+        setup_logger(&config.general.log_path, config.general.log_level, config.general.log_to_stdout)?;
     }
     *LOGGER_INITIALIZED.write().map_err(|e| MyGridInitError::LockPoisonWrite(e.to_string()))? = true;
 
     // Print version
     info!("mygrid version: {}", env!("CARGO_PKG_VERSION"));
 
-    // Set debug mode on/off
-    *DEBUG_MODE.write().map_err(|e| MyGridInitError::LockPoisonWrite(e.to_string()))? = config.general.debug_mode;
+    // Set debug mode on/off, writes to the simulated inverter are harmless so it only applies to a real one
+    let debug_mode = config.general.debug_mode && config.inverter.backend != InverterBackend::Simulator;
+    *DEBUG_MODE.write().map_err(|e| MyGridInitError::LockPoisonWrite(e.to_string()))? = debug_mode;
     if *DEBUG_MODE.read().map_err(|e| MyGridInitError::LockPoisonRead(e.to_string()))? {
         info!("running in Debug Mode!!");
     }
 
     // Instantiate time object
-    let time = UtcNow::new(config.general.debug_run_time);
+    let time = UtcNow::new(config.general.debug_run_time, config.general.debug_speed_factor);
 
     // Instantiate structs
-    let fox = Fox::new(&config.fox_ess.api_key, &config.fox_ess.inverter_sn, 30)?;
-    let mail = Mail::new(&config.mail)?;
+    let inverter: Box<dyn Inverter> = match config.inverter.backend {
+        InverterBackend::FoxEss => Box::new(Fox::new(&config.fox_ess.api_key, &config.fox_ess.inverter_sn, 30)?),
+        InverterBackend::Simulator => {
+            info!("using simulated inverter!!");
+            let parameters = config.simulator.as_ref().expect("simulator parameters are checked when loading config");
+            Box::new(Simulator::new(&config.battery, parameters, time.clone(), config.general.timezone))
+        },
+    };
+    // Offline runs against the simulator must not mail the production recipient
+    let mail = match config.inverter.backend {
+        InverterBackend::FoxEss => Mail::new(&config.mail)?,
+        InverterBackend::Simulator => {
+            info!("mails are logged only with the simulated inverter");
+            Mail::log_only()
+        },
+    };
 
 
     let mgr = Mgr {
-        fox,
+        inverter,
         mail,
         time,
     };
@@ -69,6 +79,18 @@ pub fn init() -> Result<(Config, Mgr), MyGridInitError> {
 }
 
 
+/// Returns the configuration file path given by the '--config=' argument
+///
+pub fn get_config_path() -> String {
+    env::args()
+        .find(|p| p.starts_with("--config="))
+        .expect("config file argument should be present")
+        .split_once('=')
+        .expect("config file argument should be correct")
+        .1
+        .to_string()
+}
+
 /// Reads a credential from the file system supported by the credstore and
 /// given from systemd
 ///
@@ -1,33 +1,57 @@
+use std::env;
 use std::sync::RwLock;
 use std::thread;
-use std::time::Duration;
-use chrono::{DateTime, Local, TimeDelta, Utc};
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta, Utc};
 use log::error;
-use crate::initialization::init;
+use crate::config::load_config;
+use crate::initialization::{get_config_path, init};
+use crate::manager_history::load_history;
 use crate::manager_mail::Mail;
+use crate::manual::ManualWindow;
 use crate::mode_worker::run_mode_scheduler;
 
 mod macros;
 mod initialization;
 mod manager_mail;
+mod manager_inverter;
 mod manual;
 mod config;
 mod logging;
 mod mode_scheduler;
 mod manager_files;
+mod manager_history;
 mod mode_worker;
 mod worker_common;
 mod scheduler_common;
+mod segment_diff;
+mod file_watcher;
+mod price_planner;
+mod schedule_migration;
+mod schedule_validation;
 
 /// Debug mode means no write operations to inverter (except time)
 static DEBUG_MODE: RwLock<bool> = RwLock::new(false);
 
-/// Manual day mode means no write operations to inverter (except time)
-static MANUAL_DAY: RwLock<bool> = RwLock::new(false);
+/// Manual windows as last read from the manual file, either hands off which means no write
+/// operations to inverter (except time) or overriding the schedule
+static MANUAL_WINDOWS: RwLock<Vec<ManualWindow>> = RwLock::new(Vec::new());
+
+/// Modification time of the schedule.json last written by mygrid itself, so that the file
+/// watcher can tell own writes from those of the upstream scheduler
+static SCHEDULE_WRITTEN: RwLock<Option<SystemTime>> = RwLock::new(None);
 
 static LOGGER_INITIALIZED: RwLock<bool> = RwLock::new(false);
 
 fn main() {
+    if let Some(range) = env::args().find_map(|a| a.strip_prefix("--history=").map(|r| r.to_string())) {
+        if let Err(e) = print_history(&range) {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut n_errors = 0;
     let mut last_error = Utc::now();
 
@@ -50,6 +74,26 @@ fn main() {
     }
 }
 
+/// Prints archived schedules for a date range given as 'YYYY-MM-DD..YYYY-MM-DD' or a single 'YYYY-MM-DD'
+///
+/// # Arguments
+///
+/// * 'range' - the date range to print
+fn print_history(range: &str) -> anyhow::Result<()> {
+    let (from, to) = range.split_once("..").unwrap_or((range, range));
+    let from = NaiveDate::parse_from_str(from, "%Y-%m-%d")?;
+    let to = NaiveDate::parse_from_str(to, "%Y-%m-%d")?;
+
+    let config = load_config(&get_config_path())?;
+    for day in load_history(&config.history.dir, from, to)? {
+        println!("{} (schedule id {})", day.date, day.schedule_id);
+        for block in day.blocks {
+            println!("  {} Status: {}", block, block.status);
+        }
+    }
+
+    Ok(())
+}
 
 /// Manage top level errors
 ///
@@ -93,31 +137,56 @@ fn manage_error(msg: String, mut n_errors: i32, last_error: DateTime<Utc>, mail:
     (n_errors, Utc::now())
 }
 
+#[derive(Clone)]
 pub struct UtcNow {
-    time_delta: TimeDelta,
+    real_start: DateTime<Utc>,
+    virtual_start: DateTime<Utc>,
+    speed_factor: f64,
 }
 
 impl UtcNow {
-    /// Creates a new UtcNow struct with any eventual time_delta
+    /// Creates a new UtcNow struct with any eventual start time and speed factor
     /// 
     /// # Arguments
     /// 
     /// * 'debug_run_start' - time that reflects a point in time that the worker's clock should start at
-    pub fn new(debug_run_start: Option<DateTime<Local>>) -> Self {
-        let time_delta = if let Some(debug_run_start) = debug_run_start {
-            Utc::now() - debug_run_start.with_timezone(&Utc)
-        } else {
-            TimeDelta::minutes(0)
-        };
-        
+    /// * 'debug_speed_factor' - how many times faster than real time the worker's clock should run
+    pub fn new(debug_run_start: Option<DateTime<Local>>, debug_speed_factor: Option<f64>) -> Self {
+        let real_start = Utc::now();
+        let virtual_start = debug_run_start.map_or(real_start, |d| d.with_timezone(&Utc));
+
         Self {
-            time_delta,
+            real_start,
+            virtual_start,
+            speed_factor: debug_speed_factor.filter(|f| *f > 0.0).unwrap_or(1.0),
         }
     }
 
-    /// Returns utc now with any configured time delta applied
+    /// Returns utc now with any configured start time and speed factor applied
     ///
     pub fn utc_now(&self) -> DateTime<Utc> {
-        Utc::now() - self.time_delta
+        let elapsed = Utc::now() - self.real_start;
+        let scaled = TimeDelta::milliseconds((elapsed.num_milliseconds() as f64 * self.speed_factor) as i64);
+
+        self.virtual_start + scaled
+    }
+
+    /// Sleeps for the given duration as measured by the worker's clock, i.e. the real
+    /// time slept is the duration divided by the speed factor
+    ///
+    /// # Arguments
+    ///
+    /// * 'duration' - duration to sleep in worker's clock time
+    pub fn sleep(&self, duration: Duration) {
+        thread::sleep(self.real_duration(duration));
+    }
+
+    /// Returns the real time duration corresponding to a duration in worker's clock time
+    ///
+    /// # Arguments
+    ///
+    /// * 'duration' - duration in worker's clock time
+    pub fn real_duration(&self, duration: Duration) -> Duration {
+        duration.div_f64(self.speed_factor)
     }
 }
\ No newline at end of file
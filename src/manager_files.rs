@@ -1,33 +1,107 @@
 use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::ops::Add;
+use std::fmt;
+use std::fmt::Formatter;
 use std::path::{Path, PathBuf};
-use chrono::{DateTime, NaiveDateTime, Utc};
-use log::warn;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::{info, warn};
 use thiserror::Error;
+use crate::SCHEDULE_WRITTEN;
+use crate::config::SchedulePrecedence;
+use crate::schedule_migration::parse_import_schedule;
 use crate::worker_common::{ImportSchedule, BLOCK_UNIT_SIZE};
 
+/// Dated schedule files overlapping at a point in time and which one was chosen
+#[derive(PartialEq)]
+pub struct Overlap {
+    pub chosen: String,
+    pub ignored: Vec<String>,
+    pub precedence: SchedulePrecedence,
+    pub tie: bool,
+}
+
+/// Implementation of the Display Trait for pretty print
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} chosen by {} over {}", self.chosen, self.precedence, self.ignored.join(", "))?;
+        if self.tie {
+            write!(f, " (tie, decided by file name)")?;
+        }
+        Ok(())
+    }
+}
+
 /// Gets schedule (if any) for a given date time
 ///
+/// If several dated schedule files include the date time, the one to use is decided by the
+/// given precedence, with ties decided by the highest file name. The overlap is returned
+/// alongside the schedule so that it can be reported.
+///
 /// # Arguments
 ///
+/// * 'schedule_dir' - the directory holding the schedule files
 /// * 'date_time' - the date time the schedule needs to include
-pub fn get_schedule_for_date(schedule_dir: &str, date_time: DateTime<Utc>) -> Result<Option<ImportSchedule>, FileManagerError> {
+/// * 'precedence' - how to choose among overlapping schedule files
+pub fn get_schedule_for_date(schedule_dir: &str, date_time: DateTime<Utc>, precedence: SchedulePrecedence) -> Result<Option<(ImportSchedule, Option<Overlap>)>, FileManagerError> {
+    let mut candidates: Vec<(PathBuf, ScheduleFileName, ImportSchedule)> = Vec::new();
+
     let path = format!("{}*_schedule.json", schedule_dir);
     for entry in glob::glob(&path)? {
         match entry {
             Ok(p) => {
-                let (schedule_start, schedule_end) = get_schedule_time(&p)?;
+                let Some(name) = schedule_file_name(&p) else { continue };
 
-                if date_time >= schedule_start && date_time < schedule_end {
-                    let json = fs::read_to_string(p)?;
-                    return Ok(Some(serde_json::from_str::<ImportSchedule>(&json)?))
+                if date_time >= name.start && date_time < name.end {
+                    let import_schedule = read_import_schedule(schedule_dir, &p)?;
+                    if name.schedule_id.is_some_and(|id| id != import_schedule.schedule_id) {
+                        warn!("schedule id in file name {} differs from schedule id {} in file", p.display(), import_schedule.schedule_id);
+                    }
+                    candidates.push((p, name, import_schedule));
                 }
             }
             Err(e) => warn!("{:?}", e),
         }
     }
 
-    Ok(None)
+    let mut keyed = Vec::with_capacity(candidates.len());
+    for (p, name, import_schedule) in candidates {
+        let key = match precedence {
+            SchedulePrecedence::LatestStart => PrecedenceKey::Time(name.start.into()),
+            SchedulePrecedence::HighestId => PrecedenceKey::Id(import_schedule.schedule_id),
+            SchedulePrecedence::NewestMtime => PrecedenceKey::Time(fs::metadata(&p)?.modified()?),
+        };
+        keyed.push((key, p, import_schedule));
+    }
+    keyed.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+
+    let Some((key, p, import_schedule)) = keyed.pop() else { return Ok(None) };
+
+    let overlap = if keyed.is_empty() {
+        None
+    } else {
+        let overlap = Overlap {
+            chosen: p.display().to_string(),
+            ignored: keyed.iter().rev().map(|(_, p, _)| p.display().to_string()).collect(),
+            precedence,
+            tie: keyed.last().is_some_and(|(k, _, _)| *k == key),
+        };
+        warn!("overlapping schedule files at {}: {}", date_time, overlap);
+        Some(overlap)
+    };
+
+    Ok(Some((import_schedule, overlap)))
+}
+
+/// Value compared when choosing among overlapping schedule files
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum PrecedenceKey {
+    Time(SystemTime),
+    Id(i64),
 }
 
 /// Loads scheduled blocks from file
@@ -40,8 +114,7 @@ pub fn load_scheduled_blocks(schedule_dir: &str, date_time: DateTime<Utc>) -> Re
     let file_path = format!("{}schedule.json", schedule_dir);
 
     if Path::new(&file_path).exists() {
-        let json = fs::read_to_string(file_path)?;
-        let import_schedule: ImportSchedule = serde_json::from_str(&json)?;
+        let import_schedule = read_import_schedule(schedule_dir, Path::new(&file_path))?;
 
         if import_schedule.blocks.iter().any(|b| {
             date_time >= b.start_time && date_time < b.end_time.add(chrono::Duration::minutes(BLOCK_UNIT_SIZE))
@@ -57,38 +130,214 @@ pub fn load_scheduled_blocks(schedule_dir: &str, date_time: DateTime<Utc>) -> Re
 
 /// Saves schedule to file
 ///
+/// The file is written crash safe, i.e. to a temporary file which is synced to disk and then
+/// renamed over schedule.json, all while holding an exclusive lock on the schedule lock file.
+/// The modification time of the written file is remembered, see is_own_schedule_write.
+///
 /// # Arguments
 ///
 /// * 'schedule_dir' - the directory to save the file to
 /// * 'import_schedule' - the schedule to save
 pub fn save_import_schedule(schedule_dir: &str, import_schedule: &ImportSchedule) -> Result<(), FileManagerError> {
     let file_path = format!("{}schedule.json", schedule_dir);
+    let tmp_path = format!("{}.schedule.json.tmp", schedule_dir);
 
     let json = serde_json::to_string_pretty(&import_schedule)?;
 
-    fs::write(file_path, json)?;
+    let lock = open_lock_file(schedule_dir)?;
+    lock.lock()?;
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(json.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &file_path)?;
+    File::open(schedule_dir)?.sync_all()?;
+
+    *SCHEDULE_WRITTEN.write().map_err(|e| FileManagerError::LockPoison(e.to_string()))? = Some(fs::metadata(&file_path)?.modified()?);
 
     Ok(())
 }
 
-/// Returns the date time representation of the schedule start time which is
-/// encoded in the schedule file name
+/// Checks whether a schedule file is, as far as its modification time tells, unchanged since
+/// mygrid last saved it with save_import_schedule
 ///
 /// # Arguments
 ///
-/// * 'path_buf' - the full path to the schedule file
-fn get_schedule_time(path_buf: &PathBuf) -> anyhow::Result<(DateTime<Utc>, DateTime<Utc>), FileManagerError> {
-    let file_name = path_buf.file_name()
-        .ok_or(FileManagerError::Other("error in schedule file name".to_string()))?
-        .to_str()
-        .ok_or(FileManagerError::Other("illegal character in schedule file name".to_string()))?;
+/// * 'path' - path to the schedule file
+pub fn is_own_schedule_write(path: &Path) -> bool {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
 
-    if file_name.len() != 39 {
-        Err(FileManagerError::Other("malformed schedule file name".to_string()))?
-    } else {
-        let start = NaiveDateTime::parse_from_str(&file_name[0..12], "%Y%m%d%H%M")?.and_utc();
-        let end = NaiveDateTime::parse_from_str(&file_name[13..25], "%Y%m%d%H%M")?.and_utc();
-        Ok((start, end))
+    SCHEDULE_WRITTEN.read().is_ok_and(|w| w.is_some() && *w == modified)
+}
+
+/// Reads and parses a schedule file while holding a shared lock on the schedule lock file.
+///
+/// A file that fails JSON parsing may be half-written by a producer not using the lock,
+/// so parsing is retried a few times before giving up.
+///
+/// # Arguments
+///
+/// * 'schedule_dir' - the directory holding the schedule lock file
+/// * 'path' - path to the schedule file
+fn read_import_schedule(schedule_dir: &str, path: &Path) -> Result<ImportSchedule, FileManagerError> {
+    let read = || -> Result<ImportSchedule, FileManagerError> {
+        let lock = open_lock_file(schedule_dir)?;
+        lock.lock_shared()?;
+        let json = fs::read_to_string(path)?;
+
+        parse_import_schedule(&json)
+    };
+
+    for delay_secs in [1, 2, 4] {
+        match read() {
+            Err(FileManagerError::Json(e)) => {
+                warn!("failed to parse {}: {}. Retrying in {}s", path.display(), e, delay_secs);
+                thread::sleep(Duration::from_secs(delay_secs));
+            },
+            result => return result,
+        }
+    }
+
+    read()
+}
+
+/// Opens (and creates if missing) the lock file used to coordinate access to schedule files.
+///
+/// Any producer of schedule files in the schedule directory should take an exclusive advisory
+/// lock (flock) on 'schedule.lock' in the same directory while writing, readers take a shared lock.
+/// The lock is released when the returned file is dropped.
+///
+/// # Arguments
+///
+/// * 'schedule_dir' - the directory holding the schedule files
+fn open_lock_file(schedule_dir: &str) -> Result<File, FileManagerError> {
+    let lock_path = format!("{}schedule.lock", schedule_dir);
+
+    Ok(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(lock_path)?)
+}
+
+/// Removes dated schedule files that ended before the given time
+///
+/// # Arguments
+///
+/// * 'schedule_dir' - the directory holding the schedule files
+/// * 'before' - schedule files ending before this time are removed
+pub fn purge_schedule_files(schedule_dir: &str, before: DateTime<Utc>) -> Result<(), FileManagerError> {
+    let path = format!("{}*_schedule.json", schedule_dir);
+    for entry in glob::glob(&path)? {
+        match entry {
+            Ok(p) => {
+                let Some(name) = schedule_file_name(&p) else { continue };
+
+                if name.end < before {
+                    fs::remove_file(&p)?;
+                    info!("removed old schedule file {}", p.display());
+                }
+            }
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Start, end and optional schedule id as encoded in a dated schedule file name.
+///
+/// Dated schedule files are named
+///
+/// `<start>_<end>[_<timezone>][_<schedule_id>]_schedule.json`
+///
+/// * 'start' and 'end' - given as 'YYYYMMDDHHMM', the end is exclusive
+/// * 'timezone' - optional, start and end are in UTC if left out. Either 'UTC', 'Z', a fixed offset
+///   such as '+0100' or an IANA name with '/' replaced by '.', e.g. 'Europe.Stockholm'
+/// * 'schedule_id' - optional, digits only
+///
+/// Examples: '202503300000_202503310000_schedule.json', '202503300000_202503310000_Europe.Stockholm_42_schedule.json'
+#[derive(Debug, PartialEq)]
+struct ScheduleFileName {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    schedule_id: Option<i64>,
+}
+
+/// Parses the file name of a dated schedule file, returns None and logs a warning if it is malformed
+///
+/// # Arguments
+///
+/// * 'path' - the full path to the schedule file
+fn schedule_file_name(path: &Path) -> Option<ScheduleFileName> {
+    let parsed = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(FileManagerError::Other("illegal character in schedule file name".to_string()))
+        .and_then(parse_schedule_file_name);
+
+    match parsed {
+        Ok(name) => Some(name),
+        Err(e) => {
+            warn!("skipping schedule file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Parses a dated schedule file name, see [`ScheduleFileName`] for the naming scheme
+///
+/// # Arguments
+///
+/// * 'file_name' - the file name without directory
+fn parse_schedule_file_name(file_name: &str) -> Result<ScheduleFileName, FileManagerError> {
+    let malformed = || FileManagerError::Other(format!("malformed schedule file name '{}'", file_name));
+
+    let stem = file_name.strip_suffix("_schedule.json").ok_or_else(malformed)?;
+    let mut parts = stem.split('_').collect::<Vec<&str>>();
+    if parts.len() < 2 {
+        return Err(malformed());
+    }
+
+    let schedule_id = match parts.last() {
+        Some(id) if parts.len() > 2 && id.chars().all(|c| c.is_ascii_digit()) => {
+            let id = id.parse::<i64>().map_err(|_| malformed())?;
+            parts.pop();
+            Some(id)
+        },
+        _ => None,
+    };
+
+    // IANA names may themselves contain underscores, e.g. America.Argentina.Buenos_Aires
+    let timezone = parts[2..].join("_");
+
+    let start = parse_file_name_time(parts[0], &timezone).ok_or_else(malformed)?;
+    let end = parse_file_name_time(parts[1], &timezone).ok_or_else(malformed)?;
+    if end <= start {
+        return Err(FileManagerError::Other(format!("schedule file name '{}' ends before it starts", file_name)));
+    }
+
+    Ok(ScheduleFileName { start, end, schedule_id })
+}
+
+/// Parses a 'YYYYMMDDHHMM' time from a schedule file name in the given timezone.
+/// Local times that are ambiguous due to DST resolve to the earliest instant.
+///
+/// # Arguments
+///
+/// * 'time' - the time part of the file name
+/// * 'timezone' - the timezone part of the file name, empty means UTC
+fn parse_file_name_time(time: &str, timezone: &str) -> Option<DateTime<Utc>> {
+    if time.len() != 12 || !time.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let naive = NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M").ok()?;
+
+    match timezone {
+        "" | "UTC" | "Z" => Some(naive.and_utc()),
+        tz if tz.starts_with(['+', '-']) => {
+            let offset = DateTime::parse_from_str(&format!("{} {}", time, tz), "%Y%m%d%H%M %z").ok()?;
+            Some(offset.with_timezone(&Utc))
+        },
+        tz => {
+            let tz = tz.replace('.', "/").parse::<Tz>().ok()?;
+            tz.from_local_datetime(&naive).earliest().map(|d| d.with_timezone(&Utc))
+        },
     }
 }
 
@@ -98,11 +347,104 @@ pub enum FileManagerError {
     #[error("error while reading/writing file: {0}")]
     IO(#[from] std::io::Error),
     #[error("error while parsing JSON: {0}")]
-    JSON(#[from] serde_json::Error),
+    Json(#[from] serde_json::Error),
     #[error("error while parsing glob pattern: {0}")]
-    GLOB(#[from] glob::PatternError),
+    Glob(#[from] glob::PatternError),
     #[error("error while parsing date: {0}")]
-    DATE(#[from] chrono::format::ParseError),
+    Date(#[from] chrono::format::ParseError),
+    #[error("unsupported schedule version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid schedule version: {0}")]
+    InvalidVersion(String),
+    #[error("lock poison error: {0}")]
+    LockPoison(String),
     #[error("other error: {0}")]
     Other(String),
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn legacy_name_is_utc() {
+        let file_name = "202503300000_202503310000_schedule.json";
+        assert_eq!(file_name.len(), 39);
+        assert_eq!(parse_schedule_file_name(file_name).unwrap(), ScheduleFileName {
+            start: utc("2025-03-30T00:00:00Z"),
+            end: utc("2025-03-31T00:00:00Z"),
+            schedule_id: None,
+        });
+    }
+
+    #[test]
+    fn utc_and_z_suffixes() {
+        for file_name in ["202503300000_202503310000_UTC_schedule.json", "202503300000_202503310000_Z_schedule.json"] {
+            let name = parse_schedule_file_name(file_name).unwrap();
+            assert_eq!(name.start, utc("2025-03-30T00:00:00Z"), "{}", file_name);
+            assert_eq!(name.end, utc("2025-03-31T00:00:00Z"), "{}", file_name);
+        }
+    }
+
+    #[test]
+    fn fixed_offset() {
+        let name = parse_schedule_file_name("202501010000_202501020000_+0100_schedule.json").unwrap();
+        assert_eq!(name.start, utc("2024-12-31T23:00:00Z"));
+        assert_eq!(name.end, utc("2025-01-01T23:00:00Z"));
+    }
+
+    #[test]
+    fn iana_name() {
+        // The day of the spring switch is 23 hours long
+        let name = parse_schedule_file_name("202503300000_202503310000_Europe.Stockholm_schedule.json").unwrap();
+        assert_eq!(name.start, utc("2025-03-29T23:00:00Z"));
+        assert_eq!(name.end, utc("2025-03-30T22:00:00Z"));
+        assert_eq!(name.schedule_id, None);
+    }
+
+    #[test]
+    fn iana_name_with_underscore() {
+        let name = parse_schedule_file_name("202503300000_202503310000_America.Argentina.Buenos_Aires_schedule.json").unwrap();
+        assert_eq!(name.start, utc("2025-03-30T03:00:00Z"));
+        assert_eq!(name.end, utc("2025-03-31T03:00:00Z"));
+        assert_eq!(name.schedule_id, None);
+    }
+
+    #[test]
+    fn trailing_id() {
+        let name = parse_schedule_file_name("202503300000_202503310000_42_schedule.json").unwrap();
+        assert_eq!(name.start, utc("2025-03-30T00:00:00Z"));
+        assert_eq!(name.schedule_id, Some(42));
+
+        let name = parse_schedule_file_name("202503300000_202503310000_America.Argentina.Buenos_Aires_7_schedule.json").unwrap();
+        assert_eq!(name.start, utc("2025-03-30T03:00:00Z"));
+        assert_eq!(name.schedule_id, Some(7));
+    }
+
+    #[test]
+    fn end_not_after_start() {
+        assert!(parse_schedule_file_name("202503310000_202503300000_schedule.json").is_err());
+        assert!(parse_schedule_file_name("202503300000_202503300000_schedule.json").is_err());
+    }
+
+    #[test]
+    fn bad_suffix() {
+        assert!(parse_schedule_file_name("202503300000_202503310000_schedule.txt").is_err());
+        assert!(parse_schedule_file_name("202503300000_202503310000schedule.json").is_err());
+    }
+
+    #[test]
+    fn non_digit_time() {
+        assert!(parse_schedule_file_name("2025033000x0_202503310000_schedule.json").is_err());
+        assert!(parse_schedule_file_name("202503300000_2025033100000_schedule.json").is_err());
+    }
+
+    #[test]
+    fn empty_parts() {
+        assert!(parse_schedule_file_name("a_b__schedule.json").is_err());
+        assert!(parse_schedule_file_name("_schedule.json").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use chrono::{Days, NaiveDate};
+use chrono_tz::Tz;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::worker_common::{Block, ImportSchedule};
+
+/// The final schedule of a finished day as recorded by the worker
+#[derive(Serialize, Deserialize)]
+pub struct DayHistory {
+    pub date: NaiveDate,
+    pub schedule_id: i64,
+    pub blocks: Vec<Block>,
+}
+
+/// Archives the blocks of the given local day, including status and true soc in recorded
+/// by the worker, to a dated file in the history directory
+///
+/// # Arguments
+///
+/// * 'history_dir' - the directory to archive to
+/// * 'import_schedule' - the schedule that was active during the day
+/// * 'date' - the local day to archive
+/// * 'tz' - the local timezone
+pub fn archive_day(history_dir: &str, import_schedule: &ImportSchedule, date: NaiveDate, tz: Tz) -> Result<(), HistoryError> {
+    let blocks = import_schedule.blocks.iter()
+        .filter(|b| b.start_time.with_timezone(&tz).date_naive() == date)
+        .cloned()
+        .collect::<Vec<Block>>();
+
+    if blocks.is_empty() {
+        warn!("no blocks to archive for {}", date);
+        return Ok(());
+    }
+
+    let day_history = DayHistory { date, schedule_id: import_schedule.schedule_id, blocks };
+    let json = serde_json::to_string_pretty(&day_history)?;
+
+    fs::create_dir_all(history_dir)?;
+    let file_path = format!("{}{}_history.json", history_dir, date.format("%Y%m%d"));
+    let tmp_path = format!("{}.history.json.tmp", history_dir);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(json.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &file_path)?;
+
+    info!("archived schedule for {} to {}", date, file_path);
+
+    Ok(())
+}
+
+/// Loads archived days within the given date range, both ends inclusive.
+/// Days missing in the archive are skipped.
+///
+/// # Arguments
+///
+/// * 'history_dir' - the directory holding the archive
+/// * 'from' - first day to load
+/// * 'to' - last day to load
+pub fn load_history(history_dir: &str, from: NaiveDate, to: NaiveDate) -> Result<Vec<DayHistory>, HistoryError> {
+    let mut history: Vec<DayHistory> = Vec::new();
+
+    for date in from.iter_days().take_while(|d| *d <= to) {
+        let file_path = format!("{}{}_history.json", history_dir, date.format("%Y%m%d"));
+        if Path::new(&file_path).exists() {
+            let json = fs::read_to_string(&file_path)?;
+            history.push(serde_json::from_str(&json)?);
+        }
+    }
+
+    Ok(history)
+}
+
+/// Removes archived days older than the retention period
+///
+/// # Arguments
+///
+/// * 'history_dir' - the directory holding the archive
+/// * 'today' - the current local day
+/// * 'retention_days' - number of days to keep
+pub fn purge_history(history_dir: &str, today: NaiveDate, retention_days: u64) -> Result<(), HistoryError> {
+    let oldest = today - Days::new(retention_days);
+
+    let path = format!("{}*_history.json", history_dir);
+    for entry in glob::glob(&path)? {
+        match entry {
+            Ok(p) => {
+                let date = p.file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.get(0..8))
+                    .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok());
+
+                match date {
+                    Some(d) if d < oldest => {
+                        fs::remove_file(&p)?;
+                        info!("removed archived schedule {}", p.display());
+                    },
+                    Some(_) => {},
+                    None => warn!("skipping malformed history file name {}", p.display()),
+                }
+            },
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("error while reading/writing history file: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("error while parsing history JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("error while parsing glob pattern: {0}")]
+    Glob(#[from] glob::PatternError),
+}
@@ -0,0 +1,11 @@
+use foxess::FoxError;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("error in communication with inverter: {0}")]
+pub enum InverterError {
+    #[error(transparent)]
+    FoxESS(#[from] FoxError),
+    #[error("lock poison error: {0}")]
+    LockPoison(String),
+}
@@ -0,0 +1,28 @@
+use foxess::{Fox, FoxWorkModes, Group, TimeSegmentsDataRequest};
+use foxess::fox_settings::WorkMode;
+use foxess::fox_variables::SoC;
+use crate::manager_inverter::errors::InverterError;
+use crate::manager_inverter::Inverter;
+
+/// Implementation of the Inverter trait for the FoxESS Cloud client
+impl Inverter for Fox {
+    fn set_time_segments(&self, schedule: &TimeSegmentsDataRequest) -> Result<(), InverterError> {
+        Ok(self.set_scheduler_time_segments(schedule)?)
+    }
+
+    fn get_time_segments(&self) -> Result<Vec<Group>, InverterError> {
+        Ok(self.get_scheduler_time_segments()?.groups)
+    }
+
+    fn get_work_mode(&self) -> Result<FoxWorkModes, InverterError> {
+        Ok(self.get_setting_typed::<WorkMode>()?)
+    }
+
+    fn get_soc(&self) -> Result<u8, InverterError> {
+        Ok(self.get_variable_typed::<SoC>()?)
+    }
+
+    fn get_switch_status(&self) -> Result<bool, InverterError> {
+        Ok(self.get_main_switch_status()?.enable)
+    }
+}
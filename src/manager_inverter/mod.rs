@@ -0,0 +1,60 @@
+pub mod errors;
+mod fox;
+pub mod simulator;
+
+use foxess::{ExtraParam, FoxWorkModes, Group, TimeSegmentsDataRequest};
+use crate::manager_inverter::errors::InverterError;
+
+/// Operations the mode worker needs from an inverter backend
+///
+/// Implementations should do a single attempt per call, retries and debug/manual mode
+/// checks are managed by the caller.
+pub trait Inverter {
+    /// Pushes mode scheduler time segments to the inverter
+    ///
+    /// # Arguments
+    ///
+    /// * 'schedule' - the time segments to set
+    fn set_time_segments(&self, schedule: &TimeSegmentsDataRequest) -> Result<(), InverterError>;
+
+    /// Returns the mode scheduler time segments currently set in the inverter
+    ///
+    fn get_time_segments(&self) -> Result<Vec<Group>, InverterError>;
+
+    /// Returns the work mode the inverter is currently reporting
+    ///
+    fn get_work_mode(&self) -> Result<FoxWorkModes, InverterError>;
+
+    /// Returns current battery State of Charge in percent
+    ///
+    fn get_soc(&self) -> Result<u8, InverterError>;
+
+    /// Returns whether the inverter is in Mode Scheduler mode
+    ///
+    fn get_switch_status(&self) -> Result<bool, InverterError>;
+}
+
+/// Returns a copy of a time segment group since foxess doesn't implement Clone for it
+///
+/// # Arguments
+///
+/// * 'group' - the group to copy
+pub fn copy_group(group: &Group) -> Group {
+    Group {
+        start_hour: group.start_hour,
+        start_minute: group.start_minute,
+        end_hour: group.end_hour,
+        end_minute: group.end_minute,
+        work_mode: group.work_mode,
+        extra_param: group.extra_param.as_ref().map(|e| ExtraParam {
+            fd_pwr: e.fd_pwr,
+            min_soc_on_grid: e.min_soc_on_grid,
+            fd_soc: e.fd_soc,
+            max_soc: e.max_soc,
+            import_limit: e.import_limit,
+            export_limit: e.export_limit,
+            pv_limit: e.pv_limit,
+            reactive_power: e.reactive_power,
+        }),
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::Mutex;
+use chrono::{DateTime, TimeDelta, Timelike, Utc};
+use chrono_tz::Tz;
+use foxess::{FoxWorkModes, Group, TimeSegmentsDataRequest};
+use log::info;
+use crate::config::{Battery, SimulatorParameters};
+use crate::manager_inverter::errors::InverterError;
+use crate::manager_inverter::{copy_group, Inverter};
+use crate::UtcNow;
+
+struct State {
+    soc: f64,
+    last_update: DateTime<Utc>,
+    segments: Vec<Group>,
+}
+
+/// Simulated inverter that models battery SoC from the pushed time segments
+///
+/// Charge (ForceCharge) raises SoC towards the segment's fd_soc using the configured charge power,
+/// Discharge (ForceDischarge) lowers SoC towards the segment's fd_soc using the segment's fd_pwr (W),
+/// Use (SelfUse) drains SoC towards the battery's min_soc using the configured load, and Hold (Backup)
+/// as well as any other work mode keeps it flat. Validation requires fd_pwr for Discharge blocks, a
+/// ForceDischarge segment without it keeps SoC flat rather than guessing a power.
+/// Time between segments, or before any segments are pushed, is treated as SelfUse.
+pub struct Simulator {
+    state: Mutex<State>,
+    time: UtcNow,
+    tz: Tz,
+    capacity_kwh: f64,
+    charge_power_kw: f64,
+    load_kw: f64,
+    min_soc: f64,
+}
+
+impl Simulator {
+    /// Returns a new instance of the Simulator struct
+    ///
+    /// # Arguments
+    ///
+    /// * 'battery' - battery parameters
+    /// * 'config' - simulator parameters
+    /// * 'time' - the clock to run the simulation against
+    /// * 'tz' - timezone in which time segments are given
+    pub fn new(battery: &Battery, config: &SimulatorParameters, time: UtcNow, tz: Tz) -> Self {
+        let state = State {
+            soc: config.initial_soc as f64,
+            last_update: time.utc_now(),
+            segments: Vec::new(),
+        };
+
+        Self {
+            state: Mutex::new(state),
+            time,
+            tz,
+            capacity_kwh: battery.capacity_kwh,
+            charge_power_kw: battery.charge_power_kw,
+            load_kw: config.load_kw,
+            min_soc: battery.min_soc as f64,
+        }
+    }
+
+    /// Advances the simulation up to now and runs the given function on the resulting state
+    ///
+    /// # Arguments
+    ///
+    /// * 'f' - function to run on the updated state
+    fn with_state<T>(&self, f: impl FnOnce(&mut State) -> T) -> Result<T, InverterError> {
+        let mut state = self.state.lock().map_err(|e| InverterError::LockPoison(e.to_string()))?;
+        let now = self.time.utc_now();
+
+        while state.last_update < now {
+            let step = (now - state.last_update).min(TimeDelta::minutes(1));
+            let hours = step.num_milliseconds() as f64 / 3_600_000.0;
+            let (work_mode, fd_soc, fd_pwr) = self.active_segment(state.last_update, &state.segments)
+                .map_or((FoxWorkModes::SelfUse, None, None), |s| {
+                    let extra_param = s.extra_param.as_ref();
+                    (s.work_mode, extra_param.and_then(|e| e.fd_soc), extra_param.and_then(|e| e.fd_pwr))
+                });
+
+            state.soc = match work_mode {
+                FoxWorkModes::ForceCharge => {
+                    let target = fd_soc.unwrap_or(100.0);
+                    if state.soc < target {
+                        (state.soc + self.charge_power_kw * hours / self.capacity_kwh * 100.0).min(target)
+                    } else {
+                        state.soc
+                    }
+                },
+                FoxWorkModes::ForceDischarge => {
+                    let target = fd_soc.unwrap_or(self.min_soc);
+                    let power_kw = fd_pwr.map_or(0.0, |p| p / 1000.0);
+                    if state.soc > target {
+                        (state.soc - power_kw * hours / self.capacity_kwh * 100.0).max(target)
+                    } else {
+                        state.soc
+                    }
+                },
+                FoxWorkModes::SelfUse => {
+                    if state.soc > self.min_soc {
+                        (state.soc - self.load_kw * hours / self.capacity_kwh * 100.0).max(self.min_soc)
+                    } else {
+                        state.soc
+                    }
+                },
+                _ => state.soc,
+            };
+            state.last_update += step;
+        }
+
+        Ok(f(&mut state))
+    }
+
+    /// Returns the segment active at the given time, if any
+    ///
+    /// # Arguments
+    ///
+    /// * 'date_time' - the time to find the active segment for
+    /// * 'segments' - segments to search
+    fn active_segment<'a>(&self, date_time: DateTime<Utc>, segments: &'a [Group]) -> Option<&'a Group> {
+        let local = date_time.with_timezone(&self.tz);
+        let minute_of_day = (local.hour() * 60 + local.minute()) as i64;
+
+        segments
+            .iter()
+            .find(|s| minute_of_day >= s.start_hour * 60 + s.start_minute && minute_of_day <= s.end_hour * 60 + s.end_minute)
+    }
+}
+
+/// Implementation of the Inverter trait for the simulator
+impl Inverter for Simulator {
+    fn set_time_segments(&self, schedule: &TimeSegmentsDataRequest) -> Result<(), InverterError> {
+        let segments = schedule.groups.iter().map(copy_group).collect::<Vec<_>>();
+
+        self.with_state(|s| {
+            info!("simulator: {} time segments set at soc {:.1}", segments.len(), s.soc);
+            s.segments = segments;
+        })
+    }
+
+    fn get_time_segments(&self) -> Result<Vec<Group>, InverterError> {
+        self.with_state(|s| s.segments.iter().map(copy_group).collect())
+    }
+
+    /// Reports ForceCharge and ForceDischarge as SelfUse, just as Fox ESS Cloud does
+    ///
+    fn get_work_mode(&self) -> Result<FoxWorkModes, InverterError> {
+        self.with_state(|s| {
+            match self.active_segment(s.last_update, &s.segments).map_or(FoxWorkModes::SelfUse, |g| g.work_mode) {
+                FoxWorkModes::ForceCharge | FoxWorkModes::ForceDischarge => FoxWorkModes::SelfUse,
+                work_mode => work_mode,
+            }
+        })
+    }
+
+    fn get_soc(&self) -> Result<u8, InverterError> {
+        self.with_state(|s| s.soc.round() as u8)
+    }
+
+    /// Reports Mode Scheduler mode as enabled as soon as any time segments have been set
+    ///
+    fn get_switch_status(&self) -> Result<bool, InverterError> {
+        self.with_state(|s| !s.segments.is_empty())
+    }
+}
@@ -4,15 +4,21 @@ use lettre::message::Mailbox;
 use lettre::{Message, SmtpTransport, Transport};
 use lettre::message::header::ContentType;
 use lettre::transport::smtp::authentication::Credentials;
+use log::info;
 use crate::config::MailParameters;
 use crate::manager_mail::errors::MailError;
 
-pub struct Mail {
+struct Smtp {
     sender: SmtpTransport,
     from: Mailbox,
     to: Mailbox,
 }
 
+/// Sends mails through SMTP, or only logs them if created with log_only
+pub struct Mail {
+    smtp: Option<Smtp>,
+}
+
 impl Mail {
     /// Returns a new instance of the Mail struct
     ///
@@ -30,13 +36,22 @@ impl Mail {
 
         Ok(
             Self {
-                sender,
-                from,
-                to,
+                smtp: Some(Smtp {
+                    sender,
+                    from,
+                    to,
+                }),
             }
         )
     }
 
+    /// Returns a new instance of the Mail struct that logs mails instead of sending them,
+    /// e.g. for offline runs against the simulated inverter
+    ///
+    pub fn log_only() -> Self {
+        Self { smtp: None }
+    }
+
     /// Sends a mail with the given subject and body
     ///
     /// # Arguments
@@ -44,15 +59,19 @@ impl Mail {
     /// * 'subject' - the subject of the mail
     /// * 'body' - the body of the mail
     pub fn send_mail(&self, subject: String, body: String) -> Result<(), MailError> {
+        let Some(smtp) = self.smtp.as_ref() else {
+            info!("mail not sent, {}: {}", subject, body);
+            return Ok(());
+        };
 
         let message = Message::builder()
-            .from(self.from.clone())
-            .to(self.to.clone())
+            .from(smtp.from.clone())
+            .to(smtp.to.clone())
             .subject(subject)
             .header(ContentType::TEXT_PLAIN)
             .body(body)?;
 
-        self.sender.send(&message)?;
+        smtp.sender.send(&message)?;
 
         Ok(())
     }
@@ -1,42 +1,160 @@
+use std::fmt;
+use std::fmt::Formatter;
 use std::path::Path;
-use chrono::{DateTime, Local, NaiveDate, Utc};
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+use log::warn;
 use serde::Deserialize;
 use thiserror::Error;
-use crate::MANUAL_DAY;
+use crate::MANUAL_WINDOWS;
+use crate::mode_scheduler::local_time_to_utc;
+use crate::worker_common::BLOCK_UNIT_SIZE;
 
+/// What to do during a manual window
+#[derive(Deserialize, Clone, PartialEq, Default)]
+pub enum ManualAction {
+    /// No write operations to the inverter (except time)
+    #[default]
+    HandsOff,
+    /// The schedule is overridden with SelfUse
+    SelfUse,
+    /// The schedule is overridden with charging up to soc, which is then held
+    Hold { soc: usize },
+}
+
+/// Implementation of the Display Trait for pretty print
+impl fmt::Display for ManualAction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ManualAction::HandsOff => write!(f, "HandsOff"),
+            ManualAction::SelfUse => write!(f, "SelfUse"),
+            ManualAction::Hold { soc } => write!(f, "Hold at {}", soc),
+        }
+    }
+}
+
+/// A manual window in UTC, end is exclusive
+#[derive(Clone, PartialEq)]
+pub struct ManualWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub action: ManualAction,
+}
+
+/// Implementation of the Display Trait for pretty print
+impl fmt::Display for ManualWindow {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} - {}: {}", self.start, self.end, self.action)
+    }
+}
+
+/// A window as given in the manual file, times are local and an end at or before start is on the next day
 #[derive(Deserialize)]
-struct ManualDates {
-    dates: Vec<NaiveDate>,
+struct ManualFileWindow {
+    date: NaiveDate,
+    start: NaiveTime,
+    end: NaiveTime,
+    #[serde(default)]
+    action: ManualAction,
 }
 
-/// Checks whether there is any manual file and if so read manual dates.
-/// After that it determines whether to go in to manual mode or not.
+/// The manual file, e.g.
 ///
-/// The return value depends on the manual mode when entering the function,
-/// if manual mode changes then it returns Some(true/false) otherwise it returns None
+/// '{"dates": ["2026-11-01"], "windows": [{"date": "2026-11-02", "start": "06:00", "end": "12:00", "action": {"Hold": {"soc": 80}}}]}'
 ///
+/// Whole dates are hands off, windows without an action as well.
+#[derive(Deserialize)]
+struct ManualFile {
+    #[serde(default)]
+    dates: Vec<NaiveDate>,
+    #[serde(default)]
+    windows: Vec<ManualFileWindow>,
+}
+
+/// Checks whether there is any manual file and if so reads manual dates and windows.
+/// Windows overriding the schedule must be aligned to the block unit size and hold at most 100% soc, others are skipped.
+///
+/// Returns the new set of manual windows if it differs from the one already loaded, otherwise None.
 ///
 /// # Arguments
 ///
-/// * 'manual_file' - the file holding any dates indicating going manual
-/// * 'date' - date to check if to set manual mode
-pub fn check_manual(manual_file: &str, date: DateTime<Utc>) -> Result<Option<bool>, ManualDaysError> {
-    let was_manual = *MANUAL_DAY.read().map_err(|e| ManualDaysError::LockPoisonRead(e.to_string()))?;
+/// * 'manual_file' - the file holding any manual dates and windows
+/// * 'tz' - timezone in which manual dates and windows are given
+pub fn check_manual(manual_file: &str, tz: Tz) -> Result<Option<Vec<ManualWindow>>, ManualDaysError> {
+    let mut windows: Vec<ManualWindow> = Vec::new();
 
     let path = Path::new(manual_file);
     if path.exists() {
-        let date = date.with_timezone(&Local).date_naive();
-        let json = std::fs::read_to_string(&path)?;
-        let manual: ManualDates = serde_json::from_str(&json)?;
+        let json = std::fs::read_to_string(path)?;
+        let manual: ManualFile = serde_json::from_str(&json)?;
 
-        if manual.dates.contains(&date) {
-            *MANUAL_DAY.write().map_err(|e| ManualDaysError::LockPoisonWrite(e.to_string()))? = true;
-            return if !was_manual { Ok(Some(true)) } else { Ok(None) }
+        for date in manual.dates {
+            windows.push(ManualWindow {
+                start: local_time_to_utc(date, NaiveTime::MIN, tz),
+                end: local_time_to_utc(date + Days::new(1), NaiveTime::MIN, tz),
+                action: ManualAction::HandsOff,
+            });
+        }
+
+        for w in manual.windows {
+            let aligned = [w.start, w.end].iter().all(|t| t.second() == 0 && t.minute() as i64 % BLOCK_UNIT_SIZE == 0);
+            if w.action != ManualAction::HandsOff && !aligned {
+                warn!("skipping manual window {} {}-{}: not aligned to {} minutes", w.date, w.start, w.end, BLOCK_UNIT_SIZE);
+                continue;
+            }
+            if let ManualAction::Hold { soc } = w.action && soc > 100 {
+                warn!("skipping manual window {} {}-{}: hold soc {} is above 100", w.date, w.start, w.end, soc);
+                continue;
+            }
+
+            windows.push(ManualWindow {
+                start: local_time_to_utc(w.date, w.start, tz),
+                end: local_time_to_utc(if w.end <= w.start { w.date + Days::new(1) } else { w.date }, w.end, tz),
+                action: w.action,
+            });
         }
     }
 
-    *MANUAL_DAY.write().map_err(|e| ManualDaysError::LockPoisonWrite(e.to_string()))? = false;
-    if was_manual { Ok(Some(false)) } else { Ok(None) }
+    let mut manual_windows = MANUAL_WINDOWS.write().map_err(|e| ManualDaysError::LockPoisonWrite(e.to_string()))?;
+    if *manual_windows == windows {
+        return Ok(None);
+    }
+    *manual_windows = windows.clone();
+
+    Ok(Some(windows))
+}
+
+/// Returns the action of the manual window covering the given instant, if any.
+/// Hands off has precedence should several windows cover the instant.
+///
+/// # Arguments
+///
+/// * 'instant' - the instant to check
+pub fn manual_action(instant: DateTime<Utc>) -> Result<Option<ManualAction>, ManualDaysError> {
+    let manual_windows = MANUAL_WINDOWS.read().map_err(|e| ManualDaysError::LockPoisonRead(e.to_string()))?;
+
+    let mut actions = manual_windows.iter()
+        .filter(|w| instant >= w.start && instant < w.end)
+        .map(|w| w.action.clone())
+        .collect::<Vec<ManualAction>>();
+    actions.sort_by_key(|a| *a != ManualAction::HandsOff);
+
+    Ok(actions.into_iter().next())
+}
+
+/// Returns the manual windows overriding the schedule that overlap the given period
+///
+/// # Arguments
+///
+/// * 'from' - start of the period
+/// * 'to' - end of the period (non-inclusive)
+pub fn manual_overrides(from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<ManualWindow>, ManualDaysError> {
+    let manual_windows = MANUAL_WINDOWS.read().map_err(|e| ManualDaysError::LockPoisonRead(e.to_string()))?;
+
+    Ok(manual_windows.iter()
+        .filter(|w| w.action != ManualAction::HandsOff && w.start < to && w.end > from)
+        .cloned()
+        .collect())
 }
 
 #[derive(Error, Debug)]
@@ -47,7 +165,7 @@ pub enum ManualDaysError {
     #[error("lock poison error: {0}")]
     LockPoisonWrite(String),
     #[error("error while reading manual file: {0}")]
-    JSON(#[from] serde_json::Error),
+    Json(#[from] serde_json::Error),
     #[error("error while reading manual file: {0}")]
     IO(#[from] std::io::Error),
 }
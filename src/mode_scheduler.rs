@@ -1,14 +1,34 @@
 use std::ops::Add;
-use chrono::{DateTime, DurationRound, Local, TimeDelta, Timelike, Utc};
+use chrono::{DateTime, LocalResult, NaiveDate, NaiveTime, TimeDelta, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use foxess::{ExtraParam, FoxWorkModes, Group, TimeSegmentsDataRequest};
 use log::{error, warn};
+use crate::config::{Battery, Fallback};
+use crate::manual::{manual_overrides, ManualAction, ManualWindow};
 use crate::manager_files::save_import_schedule;
 use crate::worker_common::{Block, BlockType, ImportSchedule, Status, BLOCK_UNIT_SIZE};
 use crate::manager_mail::Mail;
+use crate::schedule_migration::SCHEDULE_VERSION;
 use crate::scheduler_common::SchedulingError;
+use crate::segment_diff::format_group;
+
+/// Time segments planned for a local day and what had to be changed on the way
+struct PlannedGroups {
+    groups: Vec<Group>,
+    /// Outcome of validating the schedule's own time segments
+    validation: Result<(), SchedulingError>,
+    /// Time segments dropped on a DST switch
+    dropped: Vec<String>,
+    /// Time segments coalesced to stay within the maximum segment count
+    coalesced: Vec<String>,
+}
 
 pub struct Schedule {
     pub import_schedule: ImportSchedule,
+    tz: Tz,
+    max_segment_count: usize,
+    fallback: Fallback,
+    min_soc: usize,
 }
 
 impl Schedule {
@@ -17,97 +37,273 @@ impl Schedule {
     /// # Arguments
     ///
     /// * 'import_schedule' - The import schedule to use for the schedule
-    pub fn new(import_schedule: ImportSchedule) -> Self {
+    /// * 'tz' - timezone in which time segments are given to the inverter
+    /// * 'max_segment_count' - maximum number of time segments the inverter accepts
+    /// * 'fallback' - the fallback schedule to use if the import schedule fails validation
+    /// * 'min_soc' - the battery's min soc, see new_fallback_import_schedule
+    pub fn new(import_schedule: ImportSchedule, tz: Tz, max_segment_count: usize, fallback: &Fallback, min_soc: usize) -> Self {
         Self {
             import_schedule,
+            tz,
+            max_segment_count,
+            fallback: fallback.clone(),
+            min_soc,
         }
     }
 
-    /// Creates a new instance of an ImportSchedule
+    /// Creates a new instance of an ImportSchedule from the configured fallback schedule for the local
+    /// day of the given date time. Time not covered by any fallback block is filled with Use blocks at min soc.
     ///
-    pub fn new_default_import_schedule() -> ImportSchedule {
-        let (start_time, end_time) = get_utc_day_start(Utc::now(), 0);
+    /// # Arguments
+    ///
+    /// * 'date_time' - date time within the day the schedule should cover
+    /// * 'tz' - timezone defining the day
+    /// * 'fallback' - the fallback schedule in local time
+    /// * 'min_soc' - the battery's min soc
+    pub fn new_fallback_import_schedule(date_time: DateTime<Utc>, tz: Tz, fallback: &Fallback, min_soc: usize) -> ImportSchedule {
+        let (day_start, day_end) = get_utc_day_start(date_time, 0, tz);
+        let date = date_time.with_timezone(&tz).date_naive();
+
+        let new_block = |block_type: BlockType, start_time: DateTime<Utc>, end_time: DateTime<Utc>, soc_in: usize, soc_out: usize| Block {
+            block_id: 0,
+            block_type,
+            start_time,
+            end_time: end_time.add(-TimeDelta::minutes(BLOCK_UNIT_SIZE)),
+            cost: 0.0,
+            true_soc_in: None,
+            soc_in,
+            soc_out,
+            min_soc_on_grid: None,
+            fd_pwr: None,
+            import_limit: None,
+            export_limit: None,
+            pv_limit: None,
+            status: Status::Waiting,
+        };
+
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut covered_until = day_start;
+        for fb in &fallback.blocks {
+            let start = local_time_to_utc(date, fb.start, tz).clamp(day_start, day_end);
+            let end = if fb.end == NaiveTime::MIN { day_end } else { local_time_to_utc(date, fb.end, tz).clamp(day_start, day_end) };
 
-            ImportSchedule {
-                blocks: vec![Block {
-                    block_id: 0,
-                    block_type: BlockType::Use,
-                    start_time,
-                    end_time: end_time.add(-TimeDelta::minutes(BLOCK_UNIT_SIZE)),
-                    cost: 0.0,
-                    true_soc_in: None,
-                    soc_in: 10,
-                    soc_out: 10,
-                    status: Status::Waiting,
-                }],
-                schedule_id: 0,
+            if start > covered_until {
+                blocks.push(new_block(BlockType::Use, covered_until, start, min_soc, min_soc));
             }
+            if start < end {
+                blocks.push(Block {
+                    min_soc_on_grid: fb.min_soc_on_grid,
+                    fd_pwr: fb.fd_pwr,
+                    import_limit: fb.import_limit,
+                    export_limit: fb.export_limit,
+                    pv_limit: fb.pv_limit,
+                    ..new_block(fb.block_type.clone(), start, end, fb.soc_in, fb.soc_out)
+                });
+            }
+            covered_until = covered_until.max(end);
+        }
+        if covered_until < day_end {
+            blocks.push(new_block(BlockType::Use, covered_until, day_end, min_soc, min_soc));
+        }
+
+        for (i, b) in blocks.iter_mut().enumerate() {
+            b.block_id = i;
+        }
+
+        ImportSchedule {
+            version: SCHEDULE_VERSION,
+            blocks,
+            schedule_id: 0,
+        }
     }
 
-    /// Creates a time segments data request struct and validates it
+    /// Creates a time segments data request struct for the local day of the given date time and validates it.
+    ///
+    /// Blocks are clipped to the local day, so a schedule spanning several days gives one
+    /// set of time segments per day. Time not covered by any block is filled with SelfUse.
+    /// Adjacent time segments with identical work mode and extra params are merged, and if the
+    /// inverter's maximum segment count still is exceeded, segments are coalesced and reported by mail.
     ///
     /// # Arguments
     ///
     /// * 'mail' - Mail instance to send error messages
-    pub fn create_schedule(&self, mail: &Mail) -> TimeSegmentsDataRequest {
-        let mut groups: Vec<Group> = self.import_schedule.blocks
+    /// * 'date_time' - date time within the local day to create time segments for
+    pub fn create_schedule(&self, mail: &Mail, date_time: DateTime<Utc>) -> TimeSegmentsDataRequest {
+        let planned = self.plan_groups(date_time);
+
+        if let Err(e) = planned.validation {
+            warn!("Error in imported schedule: {}\n\nUsing fallback schedule for mode scheduler", e);
+            let _ = mail.send_mail("Mode Scheduler Error".to_string(), format!("Error in imported schedule: {}\n\nUsing fallback schedule", e));
+        }
+
+        if !planned.dropped.is_empty() {
+            let msg = format!("Time segments lying entirely within the second pass of the repeated DST hour were dropped:\n{}", planned.dropped.join("\n"));
+            warn!("{}", msg);
+            let _ = mail.send_mail("Mode Scheduler Warning".to_string(), msg);
+        }
+
+        if !planned.coalesced.is_empty() {
+            let msg = format!("Schedule needs more than {} time segments, coalesced:\n{}", self.max_segment_count, planned.coalesced.join("\n"));
+            warn!("{}", msg);
+            let _ = mail.send_mail("Mode Scheduler Warning".to_string(), msg);
+        }
+
+        TimeSegmentsDataRequest {
+            is_default: None,
+            groups: planned.groups,
+        }
+    }
+
+    /// Creates time segments for the local day of the given date time the same way as create_schedule,
+    /// but returns any problems found instead of reporting them. Used to check a schedule ahead of time.
+    ///
+    /// # Arguments
+    ///
+    /// * 'date_time' - date time within the local day to check time segments for
+    pub fn check_schedule(&self, date_time: DateTime<Utc>) -> Vec<String> {
+        let planned = self.plan_groups(date_time);
+        let mut problems: Vec<String> = Vec::new();
+
+        if let Err(e) = planned.validation {
+            problems.push(format!("Error in imported schedule: {}, fallback schedule would be used", e));
+        }
+        if !planned.dropped.is_empty() {
+            problems.push(format!("Time segments within the second pass of the repeated DST hour would be dropped:\n{}", planned.dropped.join("\n")));
+        }
+        if !planned.coalesced.is_empty() {
+            problems.push(format!("Schedule needs more than {} time segments, would coalesce:\n{}", self.max_segment_count, planned.coalesced.join("\n")));
+        }
+
+        problems
+    }
+
+    /// Creates the groups for the local day of the given date time. The fallback schedule's groups
+    /// are used if validation fails.
+    ///
+    /// # Arguments
+    ///
+    /// * 'date_time' - date time within the local day to create groups for
+    fn plan_groups(&self, date_time: DateTime<Utc>) -> PlannedGroups {
+        let (mut groups, mut dropped) = self.day_groups(date_time);
+
+        let validation = validate_schedule(&groups);
+        if validation.is_err() {
+            (groups, dropped) = self.fallback_groups(date_time);
+        }
+
+        groups = merge_adjacent_groups(groups);
+        let mut coalesced: Vec<String> = Vec::new();
+        if groups.len() > self.max_segment_count {
+            (groups, coalesced) = coalesce_groups(groups, self.max_segment_count);
+        }
+
+        PlannedGroups { groups, validation, dropped, coalesced }
+    }
+
+    /// Returns the groups of the fallback schedule for the local day of the given date time, or a
+    /// single all day SelfUse group should the fallback schedule itself not validate. Any groups
+    /// dropped on a DST switch are returned alongside, see day_groups.
+    ///
+    /// # Arguments
+    ///
+    /// * 'date_time' - date time within the local day to create groups for
+    fn fallback_groups(&self, date_time: DateTime<Utc>) -> (Vec<Group>, Vec<String>) {
+        let fallback = Schedule::new(
+            Schedule::new_fallback_import_schedule(date_time, self.tz, &self.fallback, self.min_soc),
+            self.tz,
+            self.max_segment_count,
+            &self.fallback,
+            self.min_soc,
+        );
+
+        let (groups, dropped) = fallback.day_groups(date_time);
+        if validate_schedule(&groups).is_ok() {
+            (groups, dropped)
+        } else {
+            (vec![Group {
+                start_hour: 0,
+                start_minute: 0,
+                end_hour: 23,
+                end_minute: 59,
+                work_mode: FoxWorkModes::SelfUse,
+                extra_param: None,
+            }], Vec::new())
+        }
+    }
+
+    /// Clips the blocks, with any manual overrides applied, to the local day of the given date time
+    /// and turns them into groups, adjusted for any DST switch and padded with SelfUse to cover the whole day.
+    /// Descriptions of any groups dropped by the DST adjustment are returned alongside.
+    ///
+    /// # Arguments
+    ///
+    /// * 'date_time' - date time within the local day to create groups for
+    fn day_groups(&self, date_time: DateTime<Utc>) -> (Vec<Group>, Vec<String>) {
+        let (day_start, day_end) = get_utc_day_start(date_time, 0, self.tz);
+
+        let overrides = manual_overrides(day_start, day_end).unwrap_or_else(|e| {
+            error!("failed to get manual overrides: {}", e);
+            Vec::new()
+        });
+
+        let mut groups: Vec<Group> = apply_manual_overrides(&self.import_schedule.blocks, &overrides)
             .iter()
-            .map(|b| {
-                let local_start = b.start_time.with_timezone(&Local);
-                let local_end = b.end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE - 1)).with_timezone(&Local);
-                Group {
+            .filter_map(|b| {
+                let start = b.start_time.max(day_start);
+                let end = b.end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE)).min(day_end);
+                if start >= end {
+                    return None;
+                }
+
+                let local_start = start.with_timezone(&self.tz);
+
+                // Local time jumps back on the autumn switch, a group covering the first pass of the repeated
+                // hour keeps the latest local time it reaches rather than ending in the second pass
+                let mut local_end = end.add(-TimeDelta::minutes(1)).with_timezone(&self.tz).time();
+                let mut unit_end = start.add(TimeDelta::minutes(BLOCK_UNIT_SIZE - 1));
+                while unit_end < end {
+                    local_end = local_end.max(unit_end.with_timezone(&self.tz).time());
+                    unit_end = unit_end.add(TimeDelta::minutes(BLOCK_UNIT_SIZE));
+                }
+
+                Some(Group {
                     start_hour: local_start.hour() as i64,
                     start_minute: local_start.minute() as i64,
                     end_hour: local_end.hour() as i64,
                     end_minute: local_end.minute() as i64,
                     work_mode: block_type_to_work_mode(&b.block_type),
-                    extra_param: if b.block_type == BlockType::Charge {
-                        Some(ExtraParam {
-                            fd_pwr: None,
-                            min_soc_on_grid: None,
-                            fd_soc: Some(b.soc_out as f64),
-                            max_soc: Some(b.soc_out as f64),
-                            import_limit: None,
-                            export_limit: None,
-                            pv_limit: None,
-                            reactive_power: None,
-                        })
-                    } else {
-                        None
-                    },
-                }
+                    extra_param: block_extra_param(b),
+                })
             }).collect::<Vec<_>>();
 
-        if let Some(first_block) = groups.first() {
-            if !(first_block.start_hour == 0 && first_block.start_minute == 0) {
-                groups.insert(0, Group {
-                    start_hour: 0,
-                    start_minute: 0,
-                    end_hour: if first_block.start_minute == 0 { first_block.start_hour - 1} else { first_block.start_hour },
-                    end_minute: if first_block.start_minute == 0 { 59 } else { first_block.start_minute - 1 },
-                    work_mode: FoxWorkModes::SelfUse,
-                    extra_param: None,
-                });
-            }
-        };
+        let dropped: Vec<String>;
+        (groups, dropped) = adjust_for_dst(groups, date_time.with_timezone(&self.tz).date_naive(), self.tz);
 
-        if let Err(e) = validate_schedule(&groups) {
-            groups = vec![Group {
+        if let Some(first_block) = groups.first()
+            && !(first_block.start_hour == 0 && first_block.start_minute == 0) {
+            groups.insert(0, Group {
                 start_hour: 0,
                 start_minute: 0,
+                end_hour: if first_block.start_minute == 0 { first_block.start_hour - 1} else { first_block.start_hour },
+                end_minute: if first_block.start_minute == 0 { 59 } else { first_block.start_minute - 1 },
+                work_mode: FoxWorkModes::SelfUse,
+                extra_param: None,
+            });
+        };
+
+        if let Some(last_block) = groups.last()
+            && !(last_block.end_hour == 23 && last_block.end_minute == 59) {
+            groups.push(Group {
+                start_hour: if last_block.end_minute == 59 { last_block.end_hour + 1 } else { last_block.end_hour },
+                start_minute: if last_block.end_minute == 59 { 0 } else { last_block.end_minute + 1 },
                 end_hour: 23,
                 end_minute: 59,
                 work_mode: FoxWorkModes::SelfUse,
                 extra_param: None,
-            }];
-            warn!("Error in imported schedule: {}\n\nUsing default schedule for mode scheduler", e.to_string());
-            let _ = mail.send_mail("Mode Scheduler Error".to_string(), format!("Error in imported schedule: {}\n\nUsing default schedule", e.to_string()));
-        }
+            });
+        };
 
-        TimeSegmentsDataRequest {
-            is_default: None,
-            groups,
-        }
+        (groups, dropped)
     }
 
     /// Updates the schedule with a new status if the datetime and work mode are found in it
@@ -122,9 +318,9 @@ impl Schedule {
     pub fn update_import_schedule(&mut self, schedule_dir: &str, date_time: DateTime<Utc>, work_mode: FoxWorkModes, status: Status, soc: u8) -> Result<(), SchedulingError>{
         let block_type = work_mode_to_block_type(&work_mode);
 
-        let block = self.import_schedule.blocks.iter_mut().filter(|b| {
+        let block = self.import_schedule.blocks.iter_mut().rfind(|b| {
             date_time >= b.start_time && date_time < b.end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE))
-        }).last();
+        });
 
         if let Some(b) = block && b.block_type == block_type && b.status != status {
             b.status = status;
@@ -135,19 +331,120 @@ impl Schedule {
         Ok(())
     }
     
-    /// Gets the current schedule status and work mode in the import_schedule for a given date and time
+    /// Re-plans the schedule after the battery was found at a soc deviating from the planned soc in
+    /// when a block started. The next Charge block, which may be the started block itself, gets its
+    /// soc in adjusted and is extended or shortened by the time it takes to charge the deviation, taking time
+    /// from or giving time to the adjacent following block. If it can't be extended enough, its soc out
+    /// (and thereby fd_soc) is lowered to what is reachable. The schedule is saved if anything changed.
+    ///
+    /// Returns a description of each adjustment made.
+    ///
+    /// # Arguments
+    ///
+    /// * 'schedule_dir' - the directory to save the file to
+    /// * 'block_id' - id of the block that started
+    /// * 'true_soc' - soc when the block started
+    /// * 'battery' - battery parameters
+    pub fn replan_for_soc(&mut self, schedule_dir: &str, block_id: usize, true_soc: usize, battery: &Battery) -> Result<Vec<String>, SchedulingError> {
+        let blocks = &mut self.import_schedule.blocks;
+        let mut adjustments: Vec<String> = Vec::new();
+
+        let Some(started) = blocks.iter().position(|b| b.block_id == block_id) else { return Ok(adjustments) };
+        let Some(ci) = (started..blocks.len()).find(|i| {
+            blocks[*i].block_type == BlockType::Charge && (*i == started || blocks[*i].status == Status::Waiting)
+        }) else { return Ok(adjustments) };
+
+        let deviation = true_soc as i64 - blocks[started].soc_in as i64;
+        let planned_soc_in = blocks[ci].soc_in;
+        let soc_in = (planned_soc_in as i64 + deviation).clamp(0, blocks[ci].soc_out as i64) as usize;
+        if soc_in == planned_soc_in {
+            return Ok(adjustments);
+        }
+        adjustments.push(format!("Charge block {} soc in {} -> {}", blocks[ci].block_id, planned_soc_in, soc_in));
+        blocks[ci].soc_in = soc_in;
+
+        // The block is resized by the deviation only, so that any margin in the original plan is kept.
+        // Extending rounds up to make sure soc out is reached, shortening rounds down.
+        let unit_soc = battery.charge_power_kw * BLOCK_UNIT_SIZE as f64 / 60.0 / battery.capacity_kwh * 100.0;
+        let units = (blocks[ci].end_time - blocks[ci].start_time).num_minutes() / BLOCK_UNIT_SIZE + 1;
+        let extra_units = (planned_soc_in as f64 - soc_in as f64) / unit_soc;
+        let wanted = if extra_units > 0.0 { extra_units.ceil() as i64 } else { extra_units.trunc() as i64 };
+
+        // Only an adjacent following block that hasn't started yet can give or take time
+        let next_units = blocks.get(ci + 1)
+            .filter(|n| n.start_time == blocks[ci].end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE)) && n.status == Status::Waiting)
+            .map(|n| (n.end_time - n.start_time).num_minutes() / BLOCK_UNIT_SIZE + 1);
+
+        let change = match next_units {
+            Some(next_units) => wanted.clamp(1 - units, next_units - 1),
+            None => 0,
+        };
+        if change != 0 {
+            let delta = TimeDelta::minutes(change * BLOCK_UNIT_SIZE);
+            blocks[ci].end_time = blocks[ci].end_time.add(delta);
+            blocks[ci + 1].start_time = blocks[ci + 1].start_time.add(delta);
+
+            let new_end = blocks[ci].end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE)).with_timezone(&self.tz);
+            adjustments.push(format!("Charge block {} {} by {} minutes to end at {:02}:{:02}",
+                                     blocks[ci].block_id,
+                                     if change > 0 { "extended" } else { "shortened" },
+                                     (change * BLOCK_UNIT_SIZE).abs(),
+                                     new_end.hour(), new_end.minute()));
+        }
+
+        if change < wanted {
+            let reachable = (blocks[ci].soc_out as f64 - (wanted - change) as f64 * unit_soc).round().max(soc_in as f64) as usize;
+            adjustments.push(format!("Charge block {} soc out {} -> {}", blocks[ci].block_id, blocks[ci].soc_out, reachable));
+            blocks[ci].soc_out = reachable;
+        }
+
+        if !adjustments.is_empty() {
+            save_import_schedule(schedule_dir, &self.import_schedule)?;
+        }
+
+        Ok(adjustments)
+    }
+
+    /// Checks whether any block in the schedule covers the given date time
+    ///
+    /// # Arguments
+    ///
+    /// * 'date_time' - date time to check
+    pub fn covers(&self, date_time: DateTime<Utc>) -> bool {
+        self.import_schedule.blocks.iter().any(|b| {
+            date_time >= b.start_time && date_time < b.end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE))
+        })
+    }
+
+    /// Sets a new status on the block with the given block id and saves the schedule
+    ///
+    /// # Arguments
+    ///
+    /// * 'schedule_dir' - the directory to save the file to
+    /// * 'block_id' - id of the block to update
+    /// * 'status' - the status to set the block to
+    pub fn set_block_status(&mut self, schedule_dir: &str, block_id: usize, status: Status) -> Result<(), SchedulingError> {
+        if let Some(b) = self.import_schedule.blocks.iter_mut().find(|b| b.block_id == block_id) && b.status != status {
+            b.status = status;
+            save_import_schedule(schedule_dir, &self.import_schedule)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gets the current block in the import_schedule for a given date and time
     /// 
     /// # Arguments
     ///
     /// * 'mail' - Mail instance to send error messages
     /// * 'date_time' - Date and time to check schedule status
-    pub fn get_current_schedule_status(&self, mail: &Mail, date_time: DateTime<Utc>) -> Option<(Status, FoxWorkModes)> {
-        let block = self.import_schedule.blocks.iter().filter(|b| {
+    pub fn get_current_block(&self, mail: &Mail, date_time: DateTime<Utc>) -> Option<Block> {
+        let block = self.import_schedule.blocks.iter().rfind(|b| {
             date_time >= b.start_time && date_time < b.end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE))
-        }).last();
+        });
         
         if let Some(b) = block {
-            Some((b.status.clone(), block_type_to_work_mode(&b.block_type)))
+            Some(b.clone())
         } else {
             error!("no block found for time {} when checking import schedule status", date_time);
             let _ = mail.send_mail("Mode Scheduler Error".to_string(), format!("No block found for time {} when checking import schedule status", date_time));
@@ -156,12 +453,235 @@ impl Schedule {
     }
 }
 
+/// Adjusts time segments on DST switch days so that they tile the local day.
+///
+/// On the autumn switch the repeated hour gives overlapping local times, the segment active during
+/// the first pass of the hour keeps it to the end of the hour and later segments are trimmed, or
+/// dropped if they lie entirely within the second pass. Dropped segments are logged and returned
+/// alongside so that they can be reported.
+/// On the spring switch the skipped hour gives a gap in local times, which is merged into the
+/// preceding segment. Time segments on other days are returned as is.
+///
+/// # Arguments
+///
+/// * 'groups' - time segments in chronological order
+/// * 'date' - the local date the time segments are for
+/// * 'tz' - timezone of the local date
+fn adjust_for_dst(groups: Vec<Group>, date: NaiveDate, tz: Tz) -> (Vec<Group>, Vec<String>) {
+    let mut adjusted: Vec<Group> = Vec::with_capacity(groups.len());
+    let mut dropped: Vec<String> = Vec::new();
+
+    for mut g in groups {
+        let mut start = g.start_hour * 60 + g.start_minute;
+        let end = g.end_hour * 60 + g.end_minute;
+
+        if let Some(prev) = adjusted.last_mut() {
+            let prev_end = prev.end_hour * 60 + prev.end_minute;
+
+            if start <= prev_end {
+                start = prev_end + 1;
+            } else if start > prev_end + 1 && start - prev_end <= 61 && !local_minute_exists(date, start - 1, tz) {
+                prev.end_hour = (start - 1) / 60;
+                prev.end_minute = (start - 1) % 60;
+            }
+        }
+
+        if start > end {
+            warn!("dropping time segment {} on {}, it lies within the second pass of the repeated hour", format_group(&g), date);
+            dropped.push(format_group(&g));
+            continue;
+        }
+
+        g.start_hour = start / 60;
+        g.start_minute = start % 60;
+        adjusted.push(g);
+    }
+
+    (adjusted, dropped)
+}
+
+/// Checks whether a minute of a local date exists, i.e. is not skipped by a DST switch
+///
+/// # Arguments
+///
+/// * 'date' - local date
+/// * 'minute_of_day' - minutes since local midnight
+/// * 'tz' - timezone of the local date
+fn local_minute_exists(date: NaiveDate, minute_of_day: i64, tz: Tz) -> bool {
+    let naive = date.and_time(NaiveTime::MIN).add(TimeDelta::minutes(minute_of_day));
+
+    !matches!(tz.from_local_datetime(&naive), LocalResult::None)
+}
+
+/// Returns the extra params for a block's time segment, or None if the block has none.
+/// Charge blocks get fd_soc and max_soc from soc_out, Discharge blocks get fd_soc (the floor to
+/// discharge to) from soc_out, and any block type passes on its optional min_soc_on_grid,
+/// fd_pwr (charge/discharge power), import_limit, export_limit and pv_limit.
+///
+/// # Arguments
+///
+/// * 'block' - the block to get extra params for
+fn block_extra_param(block: &Block) -> Option<ExtraParam> {
+    let (fd_soc, max_soc) = match block.block_type {
+        BlockType::Charge => (Some(block.soc_out as f64), Some(block.soc_out as f64)),
+        BlockType::Discharge => (Some(block.soc_out as f64), None),
+        _ => (None, None),
+    };
+
+    let extra_param = Some(ExtraParam {
+        fd_pwr: block.fd_pwr,
+        min_soc_on_grid: block.min_soc_on_grid,
+        fd_soc,
+        max_soc,
+        import_limit: block.import_limit,
+        export_limit: block.export_limit,
+        pv_limit: block.pv_limit,
+        reactive_power: None,
+    });
+
+    if extra_param_equal(&extra_param, &None) {
+        None
+    } else {
+        extra_param
+    }
+}
+
+/// Returns the blocks with the manual override windows cut out of them and replaced by blocks
+/// carrying out the override action, SelfUse as a Use block and Hold as a Charge block up to its soc
+///
+/// # Arguments
+///
+/// * 'blocks' - blocks in chronological order
+/// * 'overrides' - manual windows overriding the schedule
+fn apply_manual_overrides(blocks: &[Block], overrides: &[ManualWindow]) -> Vec<Block> {
+    let unit = TimeDelta::minutes(BLOCK_UNIT_SIZE);
+    let mut result = blocks.to_vec();
+
+    // Each window is cut out of what is there so far, so a later window wins over an earlier overlapping one
+    for o in overrides {
+        result = result.into_iter()
+            .flat_map(|b| {
+                [(b.start_time, b.end_time.add(unit).min(o.start)), (b.start_time.max(o.end), b.end_time.add(unit))]
+                    .into_iter()
+                    .filter(|(start, end)| start < end)
+                    .map(|(start, end)| Block { start_time: start, end_time: end.add(-unit), ..b.clone() })
+                    .collect::<Vec<Block>>()
+            })
+            .collect();
+
+        let (block_type, soc) = match o.action {
+            ManualAction::Hold { soc } => (BlockType::Charge, soc),
+            _ => (BlockType::Use, 0),
+        };
+
+        result.push(Block {
+            block_id: 0,
+            block_type,
+            start_time: o.start,
+            end_time: o.end.add(-unit),
+            cost: 0.0,
+            true_soc_in: None,
+            soc_in: soc,
+            soc_out: soc,
+            min_soc_on_grid: None,
+            fd_pwr: None,
+            import_limit: None,
+            export_limit: None,
+            pv_limit: None,
+            status: Status::Waiting,
+        });
+    }
+
+    result.sort_by_key(|b| b.start_time);
+    result
+}
+
+/// Merges adjacent time segments having identical work mode and extra params
+///
+/// # Arguments
+///
+/// * 'groups' - time segments in chronological order
+fn merge_adjacent_groups(groups: Vec<Group>) -> Vec<Group> {
+    let mut merged: Vec<Group> = Vec::with_capacity(groups.len());
+
+    for g in groups {
+        if let Some(prev) = merged.last_mut()
+            && prev.work_mode == g.work_mode
+            && extra_param_equal(&prev.extra_param, &g.extra_param) {
+            prev.end_hour = g.end_hour;
+            prev.end_minute = g.end_minute;
+        } else {
+            merged.push(g);
+        }
+    }
+
+    merged
+}
+
+/// Coalesces time segments until they are no more than the given maximum count.
+///
+/// The strategy is deterministic: the shortest segment (the earliest one if several are equally short)
+/// is absorbed by its preceding segment, or by its following segment if it is the first one of the day.
+/// The absorbing segment keeps its work mode and extra params. After each step adjacent segments
+/// are merged again if they became identical.
+///
+/// Returns the resulting segments and a description of each coalescing step.
+///
+/// # Arguments
+///
+/// * 'groups' - time segments in chronological order
+/// * 'max_count' - maximum number of time segments
+fn coalesce_groups(mut groups: Vec<Group>, max_count: usize) -> (Vec<Group>, Vec<String>) {
+    let length = |g: &Group| (g.end_hour * 60 + g.end_minute) - (g.start_hour * 60 + g.start_minute);
+    let mut coalesced: Vec<String> = Vec::new();
+
+    while groups.len() > max_count.max(1) {
+        let (index, _) = groups.iter()
+            .enumerate()
+            .min_by_key(|(i, g)| (length(g), *i))
+            .unwrap();
+
+        let removed = groups.remove(index);
+        let absorber = if index > 0 {
+            let prev = &mut groups[index - 1];
+            prev.end_hour = removed.end_hour;
+            prev.end_minute = removed.end_minute;
+            prev
+        } else {
+            let next = &mut groups[0];
+            next.start_hour = removed.start_hour;
+            next.start_minute = removed.start_minute;
+            next
+        };
+        coalesced.push(format!("{} into {}", format_group(&removed), format_group(absorber)));
+
+        groups = merge_adjacent_groups(groups);
+    }
+
+    (groups, coalesced)
+}
+
+/// Checks whether two optional extra params are equal, where None equals extra params with no values
+///
+/// # Arguments
+///
+/// * 'a' - first extra params
+/// * 'b' - second extra params
+fn extra_param_equal(a: &Option<ExtraParam>, b: &Option<ExtraParam>) -> bool {
+    let values = |e: &Option<ExtraParam>| e.as_ref().map_or([None; 8], |e| [
+        e.fd_pwr, e.min_soc_on_grid, e.fd_soc, e.max_soc,
+        e.import_limit, e.export_limit, e.pv_limit, e.reactive_power,
+    ]);
+
+    values(a) == values(b)
+}
+
 /// Validates time segments
 ///
 /// # Arguments
 ///
 /// * 'ts_groups' - Time segments to validate
-fn validate_schedule(ts_groups: &Vec<Group>) -> Result<(), SchedulingError> {
+fn validate_schedule(ts_groups: &[Group]) -> Result<(), SchedulingError> {
     let mut hour: i64 = 0;
     let mut minute: i64 = 0;
     
@@ -186,26 +706,61 @@ fn validate_schedule(ts_groups: &Vec<Group>) -> Result<(), SchedulingError> {
     Ok(())
 }
 
+/// Returns the UTC time for a local time of day. A local time falling in a DST gap
+/// is moved forward by the gap, and an ambiguous one resolves to the earliest instant.
+///
+/// # Arguments
+///
+/// * 'date' - the local date
+/// * 'time' - the local time of day
+/// * 'tz' - the local timezone
+pub fn local_time_to_utc(date: NaiveDate, time: NaiveTime, tz: Tz) -> DateTime<Utc> {
+    let naive = date.and_time(time);
+    match tz.from_local_datetime(&naive) {
+        LocalResult::Single(d) | LocalResult::Ambiguous(d, _) => d.with_timezone(&Utc),
+        LocalResult::None => {
+            let before = tz.from_local_datetime(&naive.add(-TimeDelta::hours(2))).earliest()
+                .expect("local time two hours before a DST gap should exist");
+            before.add(TimeDelta::hours(2)).with_timezone(&Utc)
+        },
+    }
+}
+
 /// Returns the start and end (non-inclusive) of a day in UTC time.
 /// For DST switch days (summer to winter time and vice versa), the length of the day
-/// will be either 23 hours (in the spring) or 25 hours (in the autumn).
+/// will be either 23 hours (in the spring) or 25 hours (in the autumn). A local midnight
+/// skipped by a DST switch resolves to the first local time of the day.
 ///
 /// # Arguments
 ///
-/// * 'date_time' - date time to get utc day start and end for (in relation to Local timezone)
+/// * 'date_time' - date time to get utc day start and end for (in relation to the given timezone)
 /// * 'day_index' - 0-based index of the day, 0 is today, -1 is yesterday, etc.
-fn get_utc_day_start(date_time: DateTime<Utc>, day_index: i64) -> (DateTime<Utc>, DateTime<Utc>) {
-    // First, go local and move hour to a safe place regarding DST day shift between summer and winter time.
-    // Also, apply the day index to get to the desired day.
-    let date = date_time.with_timezone(&Local).with_hour(12).unwrap().add(TimeDelta::days(day_index));
+/// * 'tz' - timezone defining the day
+pub fn get_utc_day_start(date_time: DateTime<Utc>, day_index: i64, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+    // Some zones switch to summer time at midnight, in which case the day starts at 01:00 local
+    let date = date_time.with_timezone(&tz).date_naive().add(TimeDelta::days(day_index));
 
-    // Then trunc to a whole hour and move time to the start of day local (Chrono manages offset change if necessary)
-    let start = date.duration_trunc(TimeDelta::hours(1)).unwrap().with_hour(0).unwrap();
+    let start = local_time_to_utc(date, NaiveTime::MIN, tz);
+    let end = local_time_to_utc(date.add(TimeDelta::days(1)), NaiveTime::MIN, tz);
 
-    // Then add one day and do the same as for start
-    let end = date.add(TimeDelta::days(1)).duration_trunc(TimeDelta::hours(1)).unwrap().with_hour(0).unwrap();
+    (start, end)
+}
 
-    (start.with_timezone(&Utc), end.with_timezone(&Utc))
+/// Checks whether a work mode reported by the inverter is what to expect for a block type.
+/// Fox ESS Cloud seem to report ForceCharge as SelfUse, hence both are accepted for Charge blocks.
+/// The same is assumed for ForceDischarge and Discharge blocks.
+///
+/// # Arguments
+///
+/// * 'block_type' - the block type to check against
+/// * 'work_mode' - work mode as reported by the inverter
+pub fn is_reported_work_mode(block_type: &BlockType, work_mode: FoxWorkModes) -> bool {
+    match block_type {
+        BlockType::Charge => work_mode == FoxWorkModes::ForceCharge || work_mode == FoxWorkModes::SelfUse,
+        BlockType::Discharge => work_mode == FoxWorkModes::ForceDischarge || work_mode == FoxWorkModes::SelfUse,
+        BlockType::Unknown => false,
+        _ => work_mode_to_block_type(&work_mode) == *block_type,
+    }
 }
 
 /// Translates between mygrid_scheduler work modes to FoxESS mode scheduler work modes
@@ -213,20 +768,442 @@ fn get_utc_day_start(date_time: DateTime<Utc>, day_index: i64) -> (DateTime<Utc>
 /// # Arguments
 ///
 /// * 'block_type' - work mode to translate
-fn block_type_to_work_mode(block_type: &BlockType) -> FoxWorkModes {
+pub fn block_type_to_work_mode(block_type: &BlockType) -> FoxWorkModes {
     match block_type {
         BlockType::Charge => FoxWorkModes::ForceCharge,
+        BlockType::Discharge => FoxWorkModes::ForceDischarge,
         BlockType::Hold => FoxWorkModes::Backup,
         BlockType::Use => FoxWorkModes::SelfUse,
+        BlockType::FeedIn => FoxWorkModes::Feedin,
+        BlockType::PeakShaving => FoxWorkModes::PeakShaving,
         BlockType::Unknown => FoxWorkModes::Unknown,
     }
 }
 
+/// Translates between FoxESS mode scheduler work modes to mygrid_scheduler work modes.
+/// Work modes without a corresponding block type are logged and translated to Unknown.
+///
+/// # Arguments
+///
+/// * 'work_mode' - work mode to translate
 fn work_mode_to_block_type(work_mode: &FoxWorkModes) -> BlockType {
     match work_mode {
         FoxWorkModes::ForceCharge => BlockType::Charge,
+        FoxWorkModes::ForceDischarge => BlockType::Discharge,
         FoxWorkModes::Backup => BlockType::Hold,
         FoxWorkModes::SelfUse => BlockType::Use,
-        _ => BlockType::Unknown,
+        FoxWorkModes::Feedin => BlockType::FeedIn,
+        FoxWorkModes::PeakShaving => BlockType::PeakShaving,
+        _ => {
+            warn!("work mode {} has no corresponding block type, treating it as Unknown", work_mode.as_str());
+            BlockType::Unknown
+        },
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Stockholm;
+    use crate::config::FallbackBlock;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    /// Builds a schedule from (block type, start, end) in UTC with exclusive ends
+    fn schedule(blocks: &[(BlockType, &str, &str)]) -> Schedule {
+        let blocks = blocks.iter().enumerate()
+            .map(|(i, (block_type, start, end))| Block {
+                block_id: i,
+                block_type: block_type.clone(),
+                start_time: utc(start),
+                end_time: utc(end).add(-TimeDelta::minutes(BLOCK_UNIT_SIZE)),
+                cost: 0.0,
+                true_soc_in: None,
+                soc_in: 10,
+                soc_out: 100,
+                min_soc_on_grid: None,
+                fd_pwr: None,
+                import_limit: None,
+                export_limit: None,
+                pv_limit: None,
+                status: Status::Waiting,
+            })
+            .collect::<Vec<Block>>();
+
+        let import_schedule = ImportSchedule { version: SCHEDULE_VERSION, blocks, schedule_id: 1 };
+        Schedule::new(import_schedule, Stockholm, 8, &Fallback { blocks: Vec::new() }, 10)
+    }
+
+    fn spans(groups: &[Group]) -> Vec<(FoxWorkModes, String)> {
+        groups.iter()
+            .map(|g| (g.work_mode, format!("{:02}:{:02}-{:02}:{:02}", g.start_hour, g.start_minute, g.end_hour, g.end_minute)))
+            .collect()
+    }
+
+    /// Builds a group from local "HH:MM" start and inclusive end, with fd_soc as its only extra param
+    fn group(work_mode: FoxWorkModes, start: &str, end: &str, fd_soc: Option<f64>) -> Group {
+        let start = NaiveTime::parse_from_str(start, "%H:%M").unwrap();
+        let end = NaiveTime::parse_from_str(end, "%H:%M").unwrap();
+
+        Group {
+            start_hour: start.hour() as i64,
+            start_minute: start.minute() as i64,
+            end_hour: end.hour() as i64,
+            end_minute: end.minute() as i64,
+            work_mode,
+            extra_param: fd_soc.map(|fd_soc| ExtraParam {
+                fd_pwr: None,
+                min_soc_on_grid: None,
+                fd_soc: Some(fd_soc),
+                max_soc: None,
+                import_limit: None,
+                export_limit: None,
+                pv_limit: None,
+                reactive_power: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn spring_switch_tiles_day() {
+        let s = schedule(&[
+            (BlockType::Use, "2025-03-29T23:00:00Z", "2025-03-30T00:00:00Z"),
+            (BlockType::Charge, "2025-03-30T00:00:00Z", "2025-03-30T01:00:00Z"),
+            (BlockType::Hold, "2025-03-30T01:00:00Z", "2025-03-30T02:00:00Z"),
+            (BlockType::Use, "2025-03-30T02:00:00Z", "2025-03-30T22:00:00Z"),
+        ]);
+
+        let (groups, _) = s.day_groups(utc("2025-03-30T10:00:00Z"));
+
+        assert!(validate_schedule(&groups).is_ok());
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-00:59".to_string()),
+            (FoxWorkModes::ForceCharge, "01:00-02:59".to_string()),
+            (FoxWorkModes::Backup, "03:00-03:59".to_string()),
+            (FoxWorkModes::SelfUse, "04:00-23:59".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn autumn_switch_tiles_day() {
+        let s = schedule(&[
+            (BlockType::Use, "2025-10-25T22:00:00Z", "2025-10-25T23:00:00Z"),
+            (BlockType::Charge, "2025-10-25T23:00:00Z", "2025-10-26T01:00:00Z"),
+            (BlockType::Hold, "2025-10-26T01:00:00Z", "2025-10-26T03:00:00Z"),
+            (BlockType::Use, "2025-10-26T03:00:00Z", "2025-10-26T23:00:00Z"),
+        ]);
+
+        let (groups, _) = s.day_groups(utc("2025-10-26T10:00:00Z"));
+
+        assert!(validate_schedule(&groups).is_ok());
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-00:59".to_string()),
+            (FoxWorkModes::ForceCharge, "01:00-02:59".to_string()),
+            (FoxWorkModes::Backup, "03:00-03:59".to_string()),
+            (FoxWorkModes::SelfUse, "04:00-23:59".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn autumn_switch_group_into_repeated_hour_keeps_first_pass() {
+        // 23:00-01:15 UTC is 01:00 CEST-02:14 CET, ending at 02:14 would switch an hour early
+        let s = schedule(&[
+            (BlockType::Use, "2025-10-25T22:00:00Z", "2025-10-25T23:00:00Z"),
+            (BlockType::Charge, "2025-10-25T23:00:00Z", "2025-10-26T01:15:00Z"),
+            (BlockType::Hold, "2025-10-26T01:15:00Z", "2025-10-26T03:00:00Z"),
+            (BlockType::Use, "2025-10-26T03:00:00Z", "2025-10-26T23:00:00Z"),
+        ]);
+
+        let (groups, _) = s.day_groups(utc("2025-10-26T10:00:00Z"));
+
+        assert!(validate_schedule(&groups).is_ok());
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-00:59".to_string()),
+            (FoxWorkModes::ForceCharge, "01:00-02:59".to_string()),
+            (FoxWorkModes::Backup, "03:00-03:59".to_string()),
+            (FoxWorkModes::SelfUse, "04:00-23:59".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn autumn_switch_group_in_second_pass_is_dropped() {
+        let s = schedule(&[
+            (BlockType::Use, "2025-10-25T22:00:00Z", "2025-10-26T01:00:00Z"),
+            (BlockType::Charge, "2025-10-26T01:00:00Z", "2025-10-26T01:30:00Z"),
+            (BlockType::Use, "2025-10-26T01:30:00Z", "2025-10-26T23:00:00Z"),
+        ]);
+
+        let (groups, dropped) = s.day_groups(utc("2025-10-26T10:00:00Z"));
+
+        assert!(validate_schedule(&groups).is_ok());
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-02:59".to_string()),
+            (FoxWorkModes::SelfUse, "03:00-23:59".to_string()),
+        ]);
+        assert_eq!(dropped.len(), 1);
+        assert!(dropped[0].starts_with("02:00-02:29 ForceCharge"), "{}", dropped[0]);
+    }
+
+    #[test]
+    fn fallback_tiles_dst_days() {
+        let fallback = Fallback {
+            blocks: vec![FallbackBlock {
+                block_type: BlockType::Charge,
+                start: NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(5, 0, 0).unwrap(),
+                soc_in: 10,
+                soc_out: 100,
+                min_soc_on_grid: None,
+                fd_pwr: None,
+                import_limit: None,
+                export_limit: None,
+                pv_limit: None,
+            }],
+        };
+
+        for date_time in [utc("2025-03-30T10:00:00Z"), utc("2025-10-26T10:00:00Z")] {
+            let import_schedule = Schedule::new_fallback_import_schedule(date_time, Stockholm, &fallback, 10);
+            let s = Schedule::new(import_schedule, Stockholm, 8, &fallback, 10);
+
+            let (groups, _) = s.day_groups(date_time);
+
+            assert!(validate_schedule(&groups).is_ok(), "{}", date_time);
+            assert!(groups.iter().any(|g| g.work_mode == FoxWorkModes::ForceCharge && g.end_hour == 4 && g.end_minute == 59), "{}", date_time);
+        }
+    }
+
+    #[test]
+    fn day_start_skipped_midnight() {
+        // Santiago and Havana switch to summer time at midnight, the day starts at 01:00 local
+        let (start, end) = get_utc_day_start(utc("2025-09-07T12:00:00Z"), 0, chrono_tz::America::Santiago);
+        assert_eq!(start, utc("2025-09-07T04:00:00Z"));
+        assert_eq!(end, utc("2025-09-08T03:00:00Z"));
+
+        let (start, end) = get_utc_day_start(utc("2025-03-08T12:00:00Z"), 1, chrono_tz::America::Havana);
+        assert_eq!(start, utc("2025-03-09T05:00:00Z"));
+        assert_eq!(end, utc("2025-03-10T04:00:00Z"));
+    }
+
+    #[test]
+    fn day_start_dst_days() {
+        assert_eq!(get_utc_day_start(utc("2025-03-30T10:00:00Z"), 0, Stockholm), (utc("2025-03-29T23:00:00Z"), utc("2025-03-30T22:00:00Z")));
+        assert_eq!(get_utc_day_start(utc("2025-10-26T10:00:00Z"), 0, Stockholm), (utc("2025-10-25T22:00:00Z"), utc("2025-10-26T23:00:00Z")));
+        assert_eq!(get_utc_day_start(utc("2025-10-27T10:00:00Z"), -1, Stockholm), (utc("2025-10-25T22:00:00Z"), utc("2025-10-26T23:00:00Z")));
+    }
+
+    #[test]
+    fn fallback_tiles_skipped_midnight() {
+        let tz = chrono_tz::America::Santiago;
+        let date_time = utc("2025-09-07T12:00:00Z");
+        let fallback = Fallback { blocks: Vec::new() };
+
+        let import_schedule = Schedule::new_fallback_import_schedule(date_time, tz, &fallback, 10);
+        let s = Schedule::new(import_schedule, tz, 8, &fallback, 10);
+
+        assert!(validate_schedule(&s.day_groups(date_time).0).is_ok());
+    }
+
+    #[test]
+    fn merge_keeps_differing_extra_params_apart() {
+        let groups = merge_adjacent_groups(vec![
+            group(FoxWorkModes::SelfUse, "00:00", "01:59", None),
+            group(FoxWorkModes::SelfUse, "02:00", "02:59", None),
+            group(FoxWorkModes::ForceCharge, "03:00", "03:59", Some(80.0)),
+            group(FoxWorkModes::ForceCharge, "04:00", "04:59", Some(100.0)),
+            group(FoxWorkModes::ForceCharge, "05:00", "05:59", Some(100.0)),
+        ]);
+
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-02:59".to_string()),
+            (FoxWorkModes::ForceCharge, "03:00-03:59".to_string()),
+            (FoxWorkModes::ForceCharge, "04:00-05:59".to_string()),
+        ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn coalesce_absorbs_into_previous() {
+        let (groups, coalesced) = coalesce_groups(vec![
+            group(FoxWorkModes::SelfUse, "00:00", "05:59", None),
+            group(FoxWorkModes::ForceCharge, "06:00", "06:29", Some(100.0)),
+            group(FoxWorkModes::Backup, "06:30", "23:59", None),
+        ], 2);
+
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-06:29".to_string()),
+            (FoxWorkModes::Backup, "06:30-23:59".to_string()),
+        ]);
+        assert_eq!(coalesced.len(), 1);
+        assert!(coalesced[0].starts_with("06:00-06:29 ForceCharge"), "{}", coalesced[0]);
+        assert!(coalesced[0].contains(" into 00:00-06:29 SelfUse"), "{}", coalesced[0]);
+    }
+
+    #[test]
+    fn coalesce_absorbs_first_into_next() {
+        let (groups, coalesced) = coalesce_groups(vec![
+            group(FoxWorkModes::ForceCharge, "00:00", "00:29", Some(100.0)),
+            group(FoxWorkModes::SelfUse, "00:30", "11:59", None),
+            group(FoxWorkModes::Backup, "12:00", "23:59", None),
+        ], 2);
+
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-11:59".to_string()),
+            (FoxWorkModes::Backup, "12:00-23:59".to_string()),
+        ]);
+        assert_eq!(coalesced.len(), 1);
+        assert!(coalesced[0].contains(" into 00:00-11:59 SelfUse"), "{}", coalesced[0]);
+    }
+
+    #[test]
+    fn coalesce_ties_take_earliest() {
+        let (groups, coalesced) = coalesce_groups(vec![
+            group(FoxWorkModes::SelfUse, "00:00", "09:59", None),
+            group(FoxWorkModes::ForceCharge, "10:00", "10:29", Some(100.0)),
+            group(FoxWorkModes::Backup, "10:30", "10:59", None),
+            group(FoxWorkModes::SelfUse, "11:00", "23:59", None),
+        ], 3);
+
+        assert_eq!(spans(&groups), vec![
+            (FoxWorkModes::SelfUse, "00:00-10:29".to_string()),
+            (FoxWorkModes::Backup, "10:30-10:59".to_string()),
+            (FoxWorkModes::SelfUse, "11:00-23:59".to_string()),
+        ]);
+        assert_eq!(coalesced.len(), 1);
+        assert!(coalesced[0].starts_with("10:00-10:29 ForceCharge"), "{}", coalesced[0]);
+    }
+
+    #[test]
+    fn coalesce_max_count_zero_and_one_leave_one_group() {
+        for max_count in [0, 1] {
+            let (groups, coalesced) = coalesce_groups(vec![
+                group(FoxWorkModes::SelfUse, "00:00", "09:59", None),
+                group(FoxWorkModes::ForceCharge, "10:00", "11:59", Some(100.0)),
+                group(FoxWorkModes::Backup, "12:00", "23:59", None),
+            ], max_count);
+
+            // the two equally long groups left tie, so the first one goes into the next
+            assert_eq!(spans(&groups), vec![(FoxWorkModes::Backup, "00:00-23:59".to_string())]);
+            assert_eq!(coalesced.len(), 2);
+        }
+    }
+
+    #[test]
+    fn coalesce_can_end_below_max_count() {
+        // merging after each step may take the count below max_count, here SelfUse and Charge
+        // both end up in Backup and the whole day becomes a single Backup segment
+        let (groups, coalesced) = coalesce_groups(vec![
+            group(FoxWorkModes::Backup, "00:00", "00:59", None),
+            group(FoxWorkModes::SelfUse, "01:00", "01:29", None),
+            group(FoxWorkModes::ForceCharge, "01:30", "02:29", Some(100.0)),
+            group(FoxWorkModes::Backup, "02:30", "23:59", None),
+        ], 2);
+
+        assert_eq!(spans(&groups), vec![(FoxWorkModes::Backup, "00:00-23:59".to_string())]);
+        assert_eq!(coalesced.len(), 2);
+        assert!(coalesced[0].starts_with("01:00-01:29 SelfUse"), "{}", coalesced[0]);
+        assert!(coalesced[1].starts_with("01:30-02:29 ForceCharge"), "{}", coalesced[1]);
+    }
+
+    /// Battery where one block unit of charging is 15% soc
+    const REPLAN_BATTERY: Battery = Battery { capacity_kwh: 10.0, charge_power_kw: 6.0, min_soc: 10, max_soc: 100 };
+
+    /// Returns an empty directory, ending with a slash, for replan_for_soc to save schedule.json in
+    fn replan_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("mygrid-replan-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        format!("{}/", dir.display())
+    }
+
+    #[test]
+    fn replan_extends_charge_rounding_up() {
+        let dir = replan_dir("extend");
+        let mut s = schedule(&[
+            (BlockType::Use, "2025-01-15T00:00:00Z", "2025-01-15T01:00:00Z"),
+            (BlockType::Charge, "2025-01-15T01:00:00Z", "2025-01-15T02:00:00Z"),
+            (BlockType::Use, "2025-01-15T02:00:00Z", "2025-01-15T04:00:00Z"),
+        ]);
+
+        // 10% short is two thirds of a unit, which rounds up to a whole unit
+        let adjustments = s.replan_for_soc(&dir, 0, 0, &REPLAN_BATTERY).unwrap();
+
+        let blocks = &s.import_schedule.blocks;
+        assert_eq!(blocks[1].soc_in, 0);
+        assert_eq!(blocks[1].soc_out, 100);
+        assert_eq!(blocks[1].end_time, utc("2025-01-15T02:00:00Z"));
+        assert_eq!(blocks[2].start_time, utc("2025-01-15T02:15:00Z"));
+        assert_eq!(adjustments, vec![
+            "Charge block 1 soc in 10 -> 0".to_string(),
+            "Charge block 1 extended by 15 minutes to end at 03:15".to_string(),
+        ]);
+        assert!(std::path::Path::new(&format!("{}schedule.json", dir)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replan_shortens_charge_rounding_down() {
+        let dir = replan_dir("shorten");
+        let mut s = schedule(&[
+            (BlockType::Use, "2025-01-15T00:00:00Z", "2025-01-15T01:00:00Z"),
+            (BlockType::Charge, "2025-01-15T01:00:00Z", "2025-01-15T02:00:00Z"),
+            (BlockType::Use, "2025-01-15T02:00:00Z", "2025-01-15T04:00:00Z"),
+        ]);
+
+        // 25% ahead is one and two thirds of a unit, only the whole unit is given away
+        let adjustments = s.replan_for_soc(&dir, 0, 35, &REPLAN_BATTERY).unwrap();
+
+        let blocks = &s.import_schedule.blocks;
+        assert_eq!(blocks[1].soc_in, 35);
+        assert_eq!(blocks[1].end_time, utc("2025-01-15T01:30:00Z"));
+        assert_eq!(blocks[2].start_time, utc("2025-01-15T01:45:00Z"));
+        assert_eq!(adjustments, vec![
+            "Charge block 1 soc in 10 -> 35".to_string(),
+            "Charge block 1 shortened by 15 minutes to end at 02:45".to_string(),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replan_without_adjacent_block_lowers_soc_out() {
+        let dir = replan_dir("clamp");
+        let mut s = schedule(&[
+            (BlockType::Use, "2025-01-15T00:00:00Z", "2025-01-15T01:00:00Z"),
+            (BlockType::Charge, "2025-01-15T01:00:00Z", "2025-01-15T02:00:00Z"),
+        ]);
+
+        let adjustments = s.replan_for_soc(&dir, 0, 0, &REPLAN_BATTERY).unwrap();
+
+        let blocks = &s.import_schedule.blocks;
+        assert_eq!(blocks[1].end_time, utc("2025-01-15T01:45:00Z"));
+        assert_eq!(blocks[1].soc_out, 85);
+        assert_eq!(adjustments, vec![
+            "Charge block 1 soc in 10 -> 0".to_string(),
+            "Charge block 1 soc out 100 -> 85".to_string(),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replan_started_charge_block() {
+        let dir = replan_dir("started");
+        let mut s = schedule(&[
+            (BlockType::Charge, "2025-01-15T01:00:00Z", "2025-01-15T02:00:00Z"),
+            (BlockType::Use, "2025-01-15T02:00:00Z", "2025-01-15T04:00:00Z"),
+            (BlockType::Charge, "2025-01-15T04:00:00Z", "2025-01-15T05:00:00Z"),
+        ]);
+        s.import_schedule.blocks[0].status = Status::Started;
+
+        let adjustments = s.replan_for_soc(&dir, 0, 0, &REPLAN_BATTERY).unwrap();
+
+        let blocks = &s.import_schedule.blocks;
+        assert_eq!(blocks[0].soc_in, 0);
+        assert_eq!(blocks[0].end_time, utc("2025-01-15T02:00:00Z"));
+        assert_eq!(blocks[1].start_time, utc("2025-01-15T02:15:00Z"));
+        assert_eq!(blocks[2].soc_in, 10);
+        assert_eq!(adjustments, vec![
+            "Charge block 0 soc in 10 -> 0".to_string(),
+            "Charge block 0 extended by 15 minutes to end at 03:15".to_string(),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
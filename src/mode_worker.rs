@@ -1,84 +1,527 @@
-use std::thread;
+use std::ops::Add;
 use std::time::Duration as StdDuration;
-use foxess::{Fox, FoxWorkModes, TimeSegmentsDataRequest};
+use foxess::{FoxWorkModes, Group, TimeSegmentsDataRequest};
 use log::{error, info, warn};
 use anyhow::Result;
-use chrono::Duration;
-use foxess::fox_settings::WorkMode;
-use foxess::fox_variables::SoC;
+use chrono::{DateTime, Days, Duration, NaiveDate, Timelike, Utc};
 use crate::retry;
 use crate::config::Config;
-use crate::worker_common::{import_schedule_from_file, is_manual_debug, WorkerError, Status};
+use crate::worker_common::{import_schedule_from_file, is_hands_off, is_manual_debug, Block, BlockType, ImportSchedule, SocAt, WorkerError, Status, BLOCK_UNIT_SIZE};
 use crate::initialization::Mgr;
+use crate::manager_files::{purge_schedule_files, FileManagerError, Overlap};
+use crate::manager_history::{archive_day, purge_history};
+use crate::manager_inverter::Inverter;
 use crate::manager_mail::Mail;
 use crate::manual::check_manual;
-use crate::mode_scheduler::Schedule;
+use crate::mode_scheduler::{block_type_to_work_mode, get_utc_day_start, is_reported_work_mode, Schedule};
+use crate::schedule_migration::SCHEDULE_VERSION;
+use crate::schedule_validation::validate_import_schedule;
+use crate::segment_diff::SegmentDiff;
+use crate::file_watcher::FileWatcher;
+use crate::price_planner::{load_spot_prices, plan_import_schedule};
 
 pub fn run_mode_scheduler(config: &Config, mgr: &mut Mgr) -> Result<(), WorkerError> {
     info!("running mode scheduler");
 
+    let watcher = FileWatcher::new(&config.files.schedule_dir, &config.files.manual_file);
     let mut instant = mgr.time.utc_now();
+    let mut last_poll: Option<DateTime<Utc>> = None;
     let mut schedule: Option<Schedule> = None;
-    let mut no_schedule_found_warned = false;
+    let mut import_warnings = ImportWarnings::default();
+    let mut lookahead = Lookahead::default();
+    let mut block_check = BlockCheck::default();
+    let mut time_segments: Option<TimeSegmentsDataRequest> = None;
+    let mut last_reconcile = mgr.time.utc_now();
+    let mut schedule_day: Option<NaiveDate> = None;
 
     loop {
-        thread::sleep(StdDuration::from_secs(10));
+        let files_changed = watcher.wait_for_change(&mgr.time, StdDuration::from_secs(10));
         let utc_now = mgr.time.utc_now();
+        let local_day = utc_now.with_timezone(&config.general.timezone).date_naive();
 
-        // Check if we should go into manual mode for today
-        if let Some(manual_mode) = check_manual(&config.files.manual_file, utc_now)? {
-            if manual_mode {
-                info!("non-automated mode activated for today");
-            } else {
-                info!("non-automated mode deactivated for today");
-            }
+        // Archive the finished day before the schedule may be replaced by the next day's
+        if let (Some(day), Some(s)) = (schedule_day, schedule.as_ref()) && day != local_day {
+            archive_schedule(config, &s.import_schedule, day, local_day, utc_now);
         }
 
-        let import_schedule = match import_schedule_from_file(&config.files.schedule_dir, utc_now)? {
-            Some(s) => {
-                no_schedule_found_warned = false;
-                s
-            },
-            None => {
-                if !no_schedule_found_warned {
-                    let _ = mgr.mail.send_mail(
-                        "Mode Scheduler Error".to_string(),
-                        "No schedule found for today, using default schedule".to_string()
-                    );
-                    warn!("no schedule found for today, using default schedule");
-                    no_schedule_found_warned = true;
-                }
-                Schedule::new_default_import_schedule()
+        // Files are re-read when the watcher reports a change, polling is a fallback and
+        // also makes sure a new day or a no longer covering schedule is picked up
+        let poll_due = last_poll.is_none_or(|t| utc_now - t >= Duration::seconds(config.files.poll_interval_seconds))
+            || schedule_day != Some(local_day)
+            || schedule.as_ref().is_none_or(|s| !s.covers(utc_now));
+
+        let mut new_schedule = false;
+        let mut manual_changed = false;
+        if files_changed || poll_due {
+            last_poll = Some(utc_now);
+
+            // Check for manual windows, changed windows may override the schedule differently
+            if let Some(windows) = check_manual(&config.files.manual_file, config.general.timezone)? {
+                info!("manual windows updated:\n{}", windows.iter().map(|w| w.to_string()).collect::<Vec<_>>().join("\n"));
+                manual_changed = true;
             }
-        };
 
-        if schedule.is_none() || import_schedule.schedule_id != schedule.as_ref().unwrap().import_schedule.schedule_id {
-            schedule = Some(Schedule::new(import_schedule));
+            let import_schedule = read_import_schedule(config, &mgr.mail, utc_now, schedule.as_ref(), &mut import_warnings)?;
+
+            // The fallback schedule keeps its id from day to day, so a current schedule that no longer
+            // covers now is replaced by a read one that does even if the ids are equal
+            new_schedule = schedule.as_ref().is_none_or(|s| {
+                import_schedule.schedule_id != s.import_schedule.schedule_id
+                    || (!s.covers(utc_now) && covers(&import_schedule, utc_now))
+            });
+            if new_schedule {
+                schedule = Some(Schedule::new(import_schedule, config.general.timezone, config.inverter.max_segment_count, &config.fallback, config.battery.min_soc));
+            }
+
+            check_tomorrow(config, &mgr.mail, utc_now, &mut lookahead)?;
+        }
+
+        // Time segments are per local day, so a new set is pushed just after midnight even if the schedule is unchanged
+        if new_schedule || manual_changed || schedule_day != Some(local_day) {
+            schedule_day = Some(local_day);
 
-            let time_segments = schedule.as_ref().unwrap().create_schedule(&mgr.mail);
+            let new_time_segments = schedule.as_ref().unwrap().create_schedule(&mgr.mail, utc_now);
+
+            set_mode_schedule_if_changed(mgr.inverter.as_ref(), &new_time_segments, utc_now)?;
+            check_switch_status(mgr.inverter.as_ref(), &mgr.mail, utc_now)?;
+            time_segments = Some(new_time_segments);
+            last_reconcile = utc_now;
+        }
 
-            set_mode_schedule(&mgr.fox, &time_segments)?;
-            check_switch_status(&mgr.fox, &mgr.mail)?;
+        if utc_now - last_reconcile >= Duration::minutes(config.worker.reconcile_interval_minutes) && let Some(ts) = time_segments.as_ref() {
+            last_reconcile = utc_now;
+            reconcile_mode_schedule(mgr.inverter.as_ref(), &mgr.mail, ts, utc_now)?;
         }
 
         if utc_now - instant > Duration::seconds(60) && let Some(s) = schedule.as_mut() {
             instant = utc_now;
+            if check_block_status(config, mgr, s, instant, &mut block_check)? {
+                let new_time_segments = s.create_schedule(&mgr.mail, utc_now);
+
+                set_mode_schedule_if_changed(mgr.inverter.as_ref(), &new_time_segments, utc_now)?;
+                time_segments = Some(new_time_segments);
+                last_reconcile = utc_now;
+            }
+        }
+    }
+}
 
-            if let Some((status, assumed_work_mode)) = s.get_current_schedule_status(&mgr.mail, instant) {
-                if status == Status::Waiting {
-                    let mut work_mode = get_working_mode(&mgr.fox)?;
-                    let soc = get_current_soc(&mgr.fox)?;
-                    info!("inverter is reporting work mode {} and soc is {}", work_mode.as_str(), soc);
-
-                    if assumed_work_mode == FoxWorkModes::ForceCharge {
-                        info!("assumed work mode is ForceCharge, but Fox ESS Cloud seem to report that as SelfUse so schedule will be updated as started anyway");
-                        work_mode = FoxWorkModes::ForceCharge;
-                    }
-                    s.update_import_schedule(&config.files.schedule_dir, instant, work_mode, Status::Started, soc)?;
+/// Archives the finished day's schedule and removes archives and dated schedule files older than
+/// the retention period. Failures are logged only since they don't affect battery operation.
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'import_schedule' - the schedule that was active during the finished day
+/// * 'day' - the finished local day
+/// * 'today' - the current local day
+/// * 'utc_now' - current time
+fn archive_schedule(config: &Config, import_schedule: &ImportSchedule, day: NaiveDate, today: NaiveDate, utc_now: DateTime<Utc>) {
+    if let Err(e) = archive_day(&config.history.dir, import_schedule, day, config.general.timezone) {
+        error!("failed to archive schedule for {}: {}", day, e);
+    }
+    if let Err(e) = purge_history(&config.history.dir, today, config.history.retention_days) {
+        error!("failed to purge schedule history: {}", e);
+    }
+    if let Err(e) = purge_schedule_files(&config.files.schedule_dir, utc_now - Days::new(config.history.retention_days)) {
+        error!("failed to purge old schedule files: {}", e);
+    }
+}
+
+/// Keeps track of which import problems have already been reported by mail
+#[derive(Default)]
+struct ImportWarnings {
+    no_schedule_found: bool,
+    unsupported_version: bool,
+    uncovered_schedule_id: Option<i64>,
+    rejected_schedule_id: Option<i64>,
+    overlap: Option<Overlap>,
+}
+
+/// Reads the import schedule covering the given time from file and validates it if it is new.
+/// If no schedule is found, if its version is unsupported or invalid, if none of its blocks covers
+/// the given time or if it fails validation, the fallback schedule is returned instead and the
+/// problem is reported by mail once.
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'mail' - the mail client to use
+/// * 'utc_now' - the time the schedule should cover
+/// * 'current' - the currently active schedule, if any
+/// * 'warnings' - problems already reported
+fn read_import_schedule(config: &Config, mail: &Mail, utc_now: DateTime<Utc>, current: Option<&Schedule>, warnings: &mut ImportWarnings) -> Result<ImportSchedule, WorkerError> {
+    let import_schedule = match import_schedule_from_file(&config.files.schedule_dir, utc_now, config.files.schedule_precedence) {
+        Ok(Some((s, overlap))) => {
+            warnings.no_schedule_found = false;
+            warnings.unsupported_version = false;
+            if overlap != warnings.overlap && let Some(o) = overlap.as_ref() {
+                let _ = mail.send_mail("Mode Scheduler Warning".to_string(), format!("Overlapping schedule files: {}", o));
+            }
+            warnings.overlap = overlap;
+
+            // A dated schedule file is chosen by the time window in its name, its blocks may still leave gaps
+            if covers(&s, utc_now) {
+                warnings.uncovered_schedule_id = None;
+                s
+            } else {
+                if warnings.uncovered_schedule_id != Some(s.schedule_id) {
+                    let msg = format!("Schedule {} has no block covering {}, using fallback schedule", s.schedule_id, utc_now);
+                    warn!("{}", msg);
+                    let _ = mail.send_mail("Mode Scheduler Warning".to_string(), msg);
+                    warnings.uncovered_schedule_id = Some(s.schedule_id);
                 }
-            } 
+                fallback_import_schedule(config, utc_now)
+            }
+        },
+        Err(WorkerError::FileManager(e @ (FileManagerError::UnsupportedVersion(_) | FileManagerError::InvalidVersion(_)))) => {
+            if !warnings.unsupported_version {
+                let msg = format!("Schedule rejected, {} (max supported version {}), using fallback schedule", e, SCHEDULE_VERSION);
+                error!("{}", msg);
+                let _ = mail.send_mail("Mode Scheduler Error".to_string(), msg);
+                warnings.unsupported_version = true;
+            }
+            fallback_import_schedule(config, utc_now)
+        },
+        Err(e) => return Err(e),
+        Ok(None) => {
+            if !warnings.no_schedule_found {
+                let _ = mail.send_mail(
+                    "Mode Scheduler Error".to_string(),
+                    "No schedule found for today, using fallback schedule".to_string()
+                );
+                warn!("no schedule found for today, using fallback schedule");
+                warnings.no_schedule_found = true;
+            }
+            fallback_import_schedule(config, utc_now)
         }
+    };
+
+    if current.is_none_or(|s| s.import_schedule.schedule_id != import_schedule.schedule_id)
+        && !is_valid_import_schedule(&import_schedule, mail, &mut warnings.rejected_schedule_id) {
+        Ok(fallback_import_schedule(config, utc_now))
+    } else {
+        Ok(import_schedule)
+    }
+}
+
+/// Checks whether any block of an import schedule covers the given date time
+///
+/// # Arguments
+///
+/// * 'import_schedule' - the schedule to check
+/// * 'date_time' - date time to check
+fn covers(import_schedule: &ImportSchedule, date_time: DateTime<Utc>) -> bool {
+    import_schedule.blocks.iter().any(|b| {
+        date_time >= b.start_time && date_time < b.end_time.add(Duration::minutes(BLOCK_UNIT_SIZE))
+    })
+}
+
+/// Keeps track of what has been checked and reported about the next local day's schedule
+#[derive(Default)]
+struct Lookahead {
+    day: Option<NaiveDate>,
+    missing_reported: bool,
+    unsupported_version_reported: bool,
+    error_reported: bool,
+    checked_schedule_id: Option<i64>,
+}
+
+/// Looks ahead to the next local day from the configured hour. Reports once by mail if no schedule
+/// covering the start of the next day exists yet, and validates a found schedule early so that any
+/// problems are reported while there is still time to fix them. Errors reading the schedule are
+/// reported once as well, they don't stop the worker since today's schedule is unaffected.
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'mail' - the mail client to use
+/// * 'utc_now' - current time
+/// * 'lookahead' - what has already been checked and reported
+fn check_tomorrow(config: &Config, mail: &Mail, utc_now: DateTime<Utc>, lookahead: &mut Lookahead) -> Result<(), WorkerError> {
+    let local_now = utc_now.with_timezone(&config.general.timezone);
+    if local_now.hour() < config.worker.lookahead_hour {
+        return Ok(());
+    }
+
+    let (tomorrow_start, _) = get_utc_day_start(utc_now, 1, config.general.timezone);
+    let tomorrow = tomorrow_start.with_timezone(&config.general.timezone).date_naive();
+    if lookahead.day != Some(tomorrow) {
+        *lookahead = Lookahead { day: Some(tomorrow), ..Default::default() };
+    }
+
+    match import_schedule_from_file(&config.files.schedule_dir, tomorrow_start, config.files.schedule_precedence) {
+        Ok(Some((import_schedule, _))) => {
+            lookahead.missing_reported = false;
+            if lookahead.checked_schedule_id == Some(import_schedule.schedule_id) {
+                return Ok(());
+            }
+            lookahead.checked_schedule_id = Some(import_schedule.schedule_id);
+
+            let schedule_id = import_schedule.schedule_id;
+            let mut problems = validate_import_schedule(&import_schedule)
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<Vec<String>>();
+            if problems.is_empty() {
+                let schedule = Schedule::new(import_schedule, config.general.timezone, config.inverter.max_segment_count, &config.fallback, config.battery.min_soc);
+                problems = schedule.check_schedule(tomorrow_start);
+            }
+
+            if problems.is_empty() {
+                info!("schedule {} for {} checked ok", schedule_id, tomorrow);
+            } else {
+                let msg = format!("Schedule {} for {} has problems:\n{}", schedule_id, tomorrow, problems.join("\n"));
+                warn!("{}", msg);
+                let _ = mail.send_mail("Mode Scheduler Lookahead".to_string(), msg);
+            }
+        },
+        Ok(None) => {
+            if !lookahead.missing_reported {
+                let msg = format!("No schedule found for {} yet, the fallback schedule will be used unless one arrives before midnight", tomorrow);
+                warn!("{}", msg);
+                let _ = mail.send_mail("Mode Scheduler Lookahead".to_string(), msg);
+                lookahead.missing_reported = true;
+            }
+        },
+        Err(WorkerError::FileManager(e @ (FileManagerError::UnsupportedVersion(_) | FileManagerError::InvalidVersion(_)))) => {
+            if !lookahead.unsupported_version_reported {
+                let msg = format!("Schedule for {} rejected, {} (max supported version {})", tomorrow, e, SCHEDULE_VERSION);
+                warn!("{}", msg);
+                let _ = mail.send_mail("Mode Scheduler Lookahead".to_string(), msg);
+                lookahead.unsupported_version_reported = true;
+            }
+        },
+        Err(e) => {
+            error!("failed to read schedule for {}: {}", tomorrow, e);
+            if !lookahead.error_reported {
+                let _ = mail.send_mail("Mode Scheduler Lookahead".to_string(), format!("Failed to read schedule for {}: {}", tomorrow, e));
+                lookahead.error_reported = true;
+            }
+        },
     }
+
+    Ok(())
+}
+
+/// Returns the schedule to use when no valid schedule exists. The day is planned from spot prices
+/// if there are prices covering it, otherwise the configured fallback schedule is used.
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'utc_now' - date time within the local day the schedule should cover
+fn fallback_import_schedule(config: &Config, utc_now: DateTime<Utc>) -> ImportSchedule {
+    let planned = match load_spot_prices(&config.price_planner.dir) {
+        Ok(prices) => plan_import_schedule(&prices, utc_now, config.general.timezone, &config.battery),
+        Err(e) => {
+            warn!("failed to load spot prices: {}", e);
+            None
+        },
+    };
+
+    planned.unwrap_or_else(|| Schedule::new_fallback_import_schedule(utc_now, config.general.timezone, &config.fallback, config.battery.min_soc))
+}
+
+/// Validates an import schedule before activation and reports any findings by mail.
+/// A rejected schedule id is remembered so that the same schedule isn't reported again.
+///
+/// # Arguments
+///
+/// * 'import_schedule' - the schedule to validate
+/// * 'mail' - the mail client to use
+/// * 'rejected_schedule_id' - id of the last rejected schedule, if any
+fn is_valid_import_schedule(import_schedule: &ImportSchedule, mail: &Mail, rejected_schedule_id: &mut Option<i64>) -> bool {
+    if *rejected_schedule_id == Some(import_schedule.schedule_id) {
+        return false;
+    }
+
+    let findings = validate_import_schedule(import_schedule);
+    if findings.is_empty() {
+        return true;
+    }
+
+    let msg = format!("Schedule {} rejected, using fallback schedule:\n{}",
+                      import_schedule.schedule_id,
+                      findings.iter().map(|f| f.to_string()).collect::<Vec<_>>().join("\n"));
+    error!("{}", msg);
+    let _ = mail.send_mail("Mode Scheduler Error".to_string(), msg);
+    *rejected_schedule_id = Some(import_schedule.schedule_id);
+
+    false
+}
+
+/// Keeps track of inverter reads made when checking the active block
+#[derive(Default)]
+struct BlockCheck {
+    /// Block id and time of the first reported work mode mismatch, if any
+    mismatch: Option<(usize, DateTime<Utc>)>,
+    /// Time the work mode was last read for a started block
+    mode_checked: Option<DateTime<Utc>>,
+}
+
+/// Checks the block active at the given time against what the inverter reports and
+/// moves it through its lifecycle, i.e. from Waiting to Started, from Started to Full when
+/// a Charge block has reached its soc_out, from Started to Empty when a Discharge block has
+/// reached its soc_out floor, and to Error when the inverter has been reporting
+/// a work mode not matching the block for longer than the configured grace period.
+///
+/// To stay within the inverter API's call limits, the work mode of a started block is only read
+/// once per reconcile interval unless a mismatch is pending, and soc is only read for a waiting
+/// block and for started Charge and Discharge blocks.
+///
+/// When a block starts at a soc deviating too much from its planned soc in, the schedule is
+/// re-planned and true is returned so that the caller pushes new time segments.
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'mgr' - manager struct
+/// * 'schedule' - the active schedule
+/// * 'date_time' - the time to check the schedule for
+/// * 'check' - inverter reads made so far
+fn check_block_status(config: &Config, mgr: &Mgr, schedule: &mut Schedule, date_time: DateTime<Utc>, check: &mut BlockCheck) -> Result<bool, WorkerError> {
+    let block = match schedule.get_current_block(&mgr.mail, date_time) {
+        Some(b) if b.status == Status::Waiting || b.status == Status::Started => b,
+        _ => return Ok(false),
+    };
+
+    let started = block.status == Status::Started;
+    let tracks_soc = block.block_type == BlockType::Charge || block.block_type == BlockType::Discharge;
+    let mismatch_pending = check.mismatch.is_some_and(|(block_id, _)| block_id == block.block_id);
+    let mode_due = !started || mismatch_pending
+        || check.mode_checked.is_none_or(|t| date_time - t >= Duration::minutes(config.worker.reconcile_interval_minutes));
+
+    if !mode_due {
+        if tracks_soc {
+            let soc = get_current_soc(mgr.inverter.as_ref())?;
+            check_block_soc(config, schedule, &block, soc, date_time)?;
+        }
+        return Ok(false);
+    }
+
+    check.mode_checked = Some(date_time);
+    let work_mode = get_working_mode(mgr.inverter.as_ref(), date_time)?;
+    info!("inverter is reporting work mode {}", work_mode.as_str());
+
+    if is_reported_work_mode(&block.block_type, work_mode) {
+        check.mismatch = None;
+
+        if !started {
+            let soc = get_current_soc(mgr.inverter.as_ref())?;
+            info!("block {} started at soc {}", block.block_id, soc);
+
+            let assumed_work_mode = block_type_to_work_mode(&block.block_type);
+            if assumed_work_mode != work_mode {
+                info!("assumed work mode is {}, but Fox ESS Cloud seem to report that as {} so schedule will be updated as started anyway", assumed_work_mode.as_str(), work_mode.as_str());
+            }
+            schedule.update_import_schedule(&config.files.schedule_dir, date_time, assumed_work_mode, Status::Started, soc)?;
+
+            if (soc as i64 - block.soc_in as i64).abs() > config.worker.soc_deviation_threshold && !is_manual_debug(date_time)? {
+                return replan_for_soc(config, &mgr.mail, schedule, &block, soc);
+            }
+        } else if tracks_soc {
+            let soc = get_current_soc(mgr.inverter.as_ref())?;
+            check_block_soc(config, schedule, &block, soc, date_time)?;
+        }
+    } else if !is_manual_debug(date_time)? {
+        let since = match check.mismatch {
+            Some((block_id, since)) if block_id == block.block_id => since,
+            _ => {
+                check.mismatch = Some((block.block_id, date_time));
+                date_time
+            },
+        };
+
+        if date_time - since >= Duration::minutes(config.worker.mode_mismatch_grace_minutes) {
+            let msg = format!("Inverter has reported work mode {} since {} while block {} is of type {}",
+                              work_mode.as_str(), since, block.block_id, block.block_type.to_string().trim_end());
+            error!("{}", msg);
+            let _ = mgr.mail.send_mail("Mode Scheduler Error".to_string(), msg);
+            schedule.set_block_status(&config.files.schedule_dir, block.block_id, Status::Error)?;
+            check.mismatch = None;
+        } else {
+            warn!("inverter work mode {} does not match block {} of type {}", work_mode.as_str(), block.block_id, block.block_type.to_string().trim_end());
+        }
+    }
+
+    Ok(false)
+}
+
+/// Sets a started Charge block to Full once it has reached its soc_out, and a started
+/// Discharge block to Empty once it has reached its soc_out floor
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'schedule' - the active schedule
+/// * 'block' - the started block
+/// * 'soc' - current soc
+/// * 'date_time' - current time
+fn check_block_soc(config: &Config, schedule: &mut Schedule, block: &Block, soc: u8, date_time: DateTime<Utc>) -> Result<(), WorkerError> {
+    if block.block_type == BlockType::Charge && soc as usize >= block.soc_out {
+        info!("charge block {} reached soc {} (soc out {})", block.block_id, soc, block.soc_out);
+        let full_at = SocAt { time: date_time, soc: soc as usize };
+        schedule.set_block_status(&config.files.schedule_dir, block.block_id, Status::Full(full_at))?;
+    } else if block.block_type == BlockType::Discharge && soc as usize <= block.soc_out {
+        info!("discharge block {} reached soc {} (soc out {})", block.block_id, soc, block.soc_out);
+        let empty_at = SocAt { time: date_time, soc: soc as usize };
+        schedule.set_block_status(&config.files.schedule_dir, block.block_id, Status::Empty(empty_at))?;
+    }
+
+    Ok(())
+}
+
+/// Re-plans the schedule when the soc at the start of a block deviates from the planned soc in by
+/// more than the configured threshold, and reports any adjustments made by mail.
+/// Returns true if the schedule was changed and time segments need to be pushed again.
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'mail' - the mail client to use
+/// * 'schedule' - the active schedule
+/// * 'block' - the block that started
+/// * 'soc' - soc at the start of the block
+fn replan_for_soc(config: &Config, mail: &Mail, schedule: &mut Schedule, block: &Block, soc: u8) -> Result<bool, WorkerError> {
+    let adjustments = schedule.replan_for_soc(&config.files.schedule_dir, block.block_id, soc as usize, &config.battery)?;
+    if adjustments.is_empty() {
+        info!("soc {} deviates from planned soc in {} for block {}, but there is nothing to re-plan", soc, block.soc_in, block.block_id);
+        return Ok(false);
+    }
+
+    let msg = format!("Soc {} at start of block {} deviates from planned soc in {} by more than {}, re-planned:\n{}",
+                      soc, block.block_id, block.soc_in, config.worker.soc_deviation_threshold, adjustments.join("\n"));
+    warn!("{}", msg);
+    let _ = mail.send_mail("Mode Scheduler Replan".to_string(), msg);
+
+    Ok(true)
+}
+
+/// Reads back the time segments from the inverter and compares them with the intended ones.
+/// If they have drifted, e.g. due to edits in the Fox app or a firmware reset, the intended
+/// time segments are pushed again and an alert mail with the diff is sent.
+///
+/// # Arguments
+///
+/// * 'inverter' - the inverter to use
+/// * 'mail' - the mail client to use
+/// * 'time_segments' - the time segments the inverter should follow
+/// * 'date_time' - current time
+fn reconcile_mode_schedule(inverter: &dyn Inverter, mail: &Mail, time_segments: &TimeSegmentsDataRequest, date_time: DateTime<Utc>) -> Result<(), WorkerError> {
+    if is_hands_off(date_time)? {return Ok(())}
+
+    let current = get_mode_schedule(inverter)?;
+    let diff = SegmentDiff::new(&current, &time_segments.groups);
+    if !diff.has_changes() {
+        return Ok(());
+    }
+
+    let msg = format!("Inverter time segments have drifted from schedule, pushing them again:\n{}", diff);
+    warn!("{}", msg);
+    let _ = mail.send_mail("Mode Scheduler Deviation".to_string(), msg);
+
+    set_mode_schedule(inverter, time_segments, date_time)
 }
 
 /// Checks if the inverter is in Mode Scheduler mode
@@ -87,18 +530,19 @@ pub fn run_mode_scheduler(config: &Config, mgr: &mut Mgr) -> Result<(), WorkerEr
 ///
 /// # Arguments
 ///
-/// * 'fox' - the foxess client to use
+/// * 'inverter' - the inverter to use
 /// * 'mail' - the mail client to use
-fn check_switch_status(fox: &Fox, mail: &Mail) -> Result<(), WorkerError> {
+/// * 'date_time' - current time
+fn check_switch_status(inverter: &dyn Inverter, mail: &Mail, date_time: DateTime<Utc>) -> Result<(), WorkerError> {
     info!("checking switch status");
-    if is_manual_debug()? {return Ok(())}
+    if is_hands_off(date_time)? {return Ok(())}
 
-    let x = retry!(
-        "fox.get_main_switch_status",
-        || fox.get_main_switch_status(),
+    let enabled = retry!(
+        "inverter.get_switch_status",
+        || inverter.get_switch_status(),
     )?;
 
-    if !x.enable {
+    if !enabled {
         error!("Inverter switch status indicates inverter is not in Mode Scheduler mode");
         let _ = mail.send_mail("Mode Scheduler Error".to_string(), "Inverter switch status indicates inverter is not in Mode Scheduler mode".to_string());
     }
@@ -107,18 +551,19 @@ fn check_switch_status(fox: &Fox, mail: &Mail) -> Result<(), WorkerError> {
 }
 
 
-/// Get the current work mode from FoxESS
+/// Get the current work mode from the inverter
 ///
 /// # Arguments
 ///
-/// * 'fox' - the foxess client to use
-fn get_working_mode(fox: &Fox) -> Result<FoxWorkModes, WorkerError> {
+/// * 'inverter' - the inverter to use
+/// * 'date_time' - current time
+fn get_working_mode(inverter: &dyn Inverter, date_time: DateTime<Utc>) -> Result<FoxWorkModes, WorkerError> {
     info!("getting working mode");
-    if is_manual_debug()? {return Ok(FoxWorkModes::SelfUse)}
+    if is_hands_off(date_time)? {return Ok(FoxWorkModes::SelfUse)}
 
     let wm = retry!(
-        "get_setting_typed::<WorkMode>",
-        || fox.get_setting_typed::<WorkMode>(),
+        "inverter.get_work_mode",
+        || inverter.get_work_mode(),
     )?;
 
     Ok(wm)
@@ -128,28 +573,64 @@ fn get_working_mode(fox: &Fox) -> Result<FoxWorkModes, WorkerError> {
 ///
 /// # Arguments
 ///
-/// * 'fox' - the foxess client to use
+/// * 'inverter' - the inverter to use
 /// * 'schedule' - the schedule to set
-fn set_mode_schedule(fox: &Fox, schedule: &TimeSegmentsDataRequest) -> Result<(), WorkerError> {
+/// * 'date_time' - current time
+fn set_mode_schedule(inverter: &dyn Inverter, schedule: &TimeSegmentsDataRequest, date_time: DateTime<Utc>) -> Result<(), WorkerError> {
     info!("setting mode scheduler schedule");
-    if is_manual_debug()? {return Ok(())}
+    if is_hands_off(date_time)? {return Ok(())}
 
-    let _ = retry!(
-        "set_scheduler_time_segments",
-        || fox.set_scheduler_time_segments(schedule),
+    retry!(
+        "inverter.set_time_segments",
+        || inverter.set_time_segments(schedule),
     )?;
 
     Ok(())
 }
 
+/// Get mode scheduler schedule currently set in the inverter
+///
+/// # Arguments
+///
+/// * 'inverter' - the inverter to use
+fn get_mode_schedule(inverter: &dyn Inverter) -> Result<Vec<Group>, WorkerError> {
+    info!("getting mode scheduler schedule");
+
+    Ok(retry!(
+        "inverter.get_time_segments",
+        || inverter.get_time_segments(),
+    )?)
+}
+
+/// Set mode scheduler schedule unless the inverter already has identical time segments
+///
+/// # Arguments
+///
+/// * 'inverter' - the inverter to use
+/// * 'schedule' - the schedule to set
+/// * 'date_time' - current time
+fn set_mode_schedule_if_changed(inverter: &dyn Inverter, schedule: &TimeSegmentsDataRequest, date_time: DateTime<Utc>) -> Result<(), WorkerError> {
+    if is_hands_off(date_time)? {return Ok(())}
+
+    let current = get_mode_schedule(inverter)?;
+    let diff = SegmentDiff::new(&current, &schedule.groups);
+    if !diff.has_changes() {
+        info!("inverter already has the mode scheduler schedule, skipping write");
+        return Ok(());
+    }
+
+    info!("mode scheduler schedule differs from inverter:\n{}", diff);
+    set_mode_schedule(inverter, schedule, date_time)
+}
+
 /// Returns current State of Charge
 ///
 /// # Arguments
 ///
-/// * 'fox' - reference to the Fox struct
-fn get_current_soc(fox: &Fox) -> Result<u8, WorkerError> {
+/// * 'inverter' - the inverter to use
+fn get_current_soc(inverter: &dyn Inverter) -> Result<u8, WorkerError> {
     Ok(retry!(
-        "get_variable_typed::<SoC>",
-        ||fox.get_variable_typed::<SoC>(),
+        "inverter.get_soc",
+        || inverter.get_soc(),
     )?)
 }
\ No newline at end of file
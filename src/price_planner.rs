@@ -0,0 +1,201 @@
+use std::fs;
+use std::ops::Add;
+use std::path::Path;
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use log::{info, warn};
+use serde::Deserialize;
+use thiserror::Error;
+use crate::config::Battery;
+use crate::mode_scheduler::get_utc_day_start;
+use crate::schedule_migration::SCHEDULE_VERSION;
+use crate::worker_common::{Block, BlockType, ImportSchedule, Status, BLOCK_UNIT_SIZE};
+
+/// Spot price for a period of time as read from a price file.
+///
+/// Price files are dropped into the price directory as either
+///
+/// * JSON - an array of objects, e.g. '[{"start": "2025-03-30T00:00:00Z", "end": "2025-03-30T01:00:00Z", "price": 0.52}]'
+/// * CSV - one 'start,end,price' row per period with RFC 3339 times, an optional header row is skipped
+///
+/// Periods may be of any length, e.g. 15 minutes or an hour, and may span several files.
+#[derive(Deserialize)]
+pub struct SpotPrice {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub price: f64,
+}
+
+/// Loads spot prices from all JSON and CSV files in the price directory
+///
+/// # Arguments
+///
+/// * 'price_dir' - the directory holding price files
+pub fn load_spot_prices(price_dir: &str) -> Result<Vec<SpotPrice>, PricePlannerError> {
+    let mut prices: Vec<SpotPrice> = Vec::new();
+
+    for pattern in ["*.json", "*.csv"] {
+        for entry in glob::glob(&format!("{}{}", price_dir, pattern))? {
+            match entry {
+                Ok(p) => prices.append(&mut read_price_file(&p)?),
+                Err(e) => warn!("{:?}", e),
+            }
+        }
+    }
+
+    Ok(prices)
+}
+
+/// Reads one price file, the format is given by the file extension
+///
+/// # Arguments
+///
+/// * 'path' - path to the price file
+fn read_price_file(path: &Path) -> Result<Vec<SpotPrice>, PricePlannerError> {
+    let content = fs::read_to_string(path)?;
+
+    if path.extension().is_some_and(|e| e == "json") {
+        return Ok(serde_json::from_str(&content)?);
+    }
+
+    let mut prices: Vec<SpotPrice> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let fields = line.split(',').map(|f| f.trim()).collect::<Vec<&str>>();
+        if fields.iter().all(|f| f.is_empty()) {
+            continue;
+        }
+        if fields.len() != 3 {
+            return Err(PricePlannerError::Csv(format!("{} line {}: expected 3 fields", path.display(), i + 1)));
+        }
+
+        let start = DateTime::parse_from_rfc3339(fields[0]);
+        if i == 0 && start.is_err() {
+            continue;
+        }
+
+        let parse_error = |e: String| PricePlannerError::Csv(format!("{} line {}: {}", path.display(), i + 1, e));
+        prices.push(SpotPrice {
+            start: start.map_err(|e| parse_error(e.to_string()))?.with_timezone(&Utc),
+            end: DateTime::parse_from_rfc3339(fields[1]).map_err(|e| parse_error(e.to_string()))?.with_timezone(&Utc),
+            price: fields[2].parse::<f64>().map_err(|e| parse_error(e.to_string()))?,
+        });
+    }
+
+    Ok(prices)
+}
+
+/// Plans the local day of the given date time from spot prices.
+///
+/// The battery is charged from min soc to max soc in the cheapest consecutive units of the day. The battery is
+/// used in the units priced at or above the day's average, and held in the remaining cheap units so
+/// that its energy is saved for the expensive ones. Returns None if the prices don't cover the whole day.
+///
+/// Locally planned schedules get a negative schedule id derived from the day, so that they differ
+/// from both the fallback schedule and schedules from the upstream scheduler.
+///
+/// # Arguments
+///
+/// * 'prices' - spot prices
+/// * 'date_time' - date time within the local day to plan
+/// * 'tz' - timezone defining the day
+/// * 'battery' - battery parameters
+pub fn plan_import_schedule(prices: &[SpotPrice], date_time: DateTime<Utc>, tz: Tz, battery: &Battery) -> Option<ImportSchedule> {
+    let (day_start, day_end) = get_utc_day_start(date_time, 0, tz);
+
+    let mut unit_prices: Vec<f64> = Vec::new();
+    let mut unit_start = day_start;
+    while unit_start < day_end {
+        match prices.iter().find(|p| unit_start >= p.start && unit_start < p.end) {
+            Some(p) => unit_prices.push(p.price),
+            None => {
+                info!("no spot price for {}, can't plan the day from prices", unit_start);
+                return None;
+            },
+        }
+        unit_start = unit_start.add(TimeDelta::minutes(BLOCK_UNIT_SIZE));
+    }
+
+    let unit_kwh = battery.charge_power_kw * BLOCK_UNIT_SIZE as f64 / 60.0;
+    let unit_soc = unit_kwh / battery.capacity_kwh * 100.0;
+    let charge_kwh = battery.max_soc.saturating_sub(battery.min_soc) as f64 / 100.0 * battery.capacity_kwh;
+    let charge_units = ((charge_kwh / unit_kwh).ceil() as usize).min(unit_prices.len());
+
+    // Charging in one go keeps the number of time segments down
+    let charge_start = (0..=unit_prices.len() - charge_units)
+        .min_by(|a, b| {
+            let sum = |i: usize| unit_prices[i..i + charge_units].iter().sum::<f64>();
+            sum(*a).total_cmp(&sum(*b))
+        })
+        .unwrap_or(0);
+    let charge = charge_start..charge_start + charge_units;
+    let average = unit_prices.iter().sum::<f64>() / unit_prices.len() as f64;
+
+    let unit_types = (0..unit_prices.len())
+        .map(|i| {
+            if charge.contains(&i) {
+                BlockType::Charge
+            } else if unit_prices[i] >= average {
+                BlockType::Use
+            } else {
+                BlockType::Hold
+            }
+        })
+        .collect::<Vec<BlockType>>();
+
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut soc = battery.min_soc;
+    let mut first = 0;
+    while first < unit_types.len() {
+        let block_type = unit_types[first].clone();
+        let last = (first..unit_types.len()).take_while(|i| unit_types[*i] == block_type).last().unwrap_or(first);
+        let units = last - first + 1;
+
+        let soc_in = soc;
+        let (soc_out, cost) = match block_type {
+            BlockType::Charge => {
+                let soc_out = ((soc as f64 + unit_soc * units as f64).round() as usize).min(battery.max_soc);
+                (soc_out, unit_prices[first..=last].iter().sum::<f64>() * unit_kwh)
+            },
+            BlockType::Use => (battery.min_soc, 0.0),
+            _ => (soc, 0.0),
+        };
+        soc = soc_out;
+
+        blocks.push(Block {
+            block_id: blocks.len(),
+            block_type,
+            start_time: day_start.add(TimeDelta::minutes(first as i64 * BLOCK_UNIT_SIZE)),
+            end_time: day_start.add(TimeDelta::minutes(last as i64 * BLOCK_UNIT_SIZE)),
+            cost,
+            true_soc_in: None,
+            soc_in,
+            soc_out,
+            min_soc_on_grid: None,
+            fd_pwr: None,
+            import_limit: None,
+            export_limit: None,
+            pv_limit: None,
+            status: Status::Waiting,
+        });
+
+        first = last + 1;
+    }
+
+    Some(ImportSchedule {
+        version: SCHEDULE_VERSION,
+        blocks,
+        schedule_id: -day_start.timestamp(),
+    })
+}
+
+#[derive(Error, Debug)]
+pub enum PricePlannerError {
+    #[error("error while reading price file: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("error while parsing price JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("error while parsing price CSV: {0}")]
+    Csv(String),
+    #[error("error while parsing glob pattern: {0}")]
+    Glob(#[from] glob::PatternError),
+}
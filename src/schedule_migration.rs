@@ -0,0 +1,53 @@
+use serde_json::Value;
+use crate::manager_files::FileManagerError;
+use crate::worker_common::ImportSchedule;
+
+/// Current version of the ImportSchedule JSON format, see schema/import_schedule.schema.json
+///
+/// * 1 - unversioned format with Charge, Hold and Use blocks
+/// * 2 - adds the version field, optional per-block extra params, and Discharge, FeedIn and PeakShaving blocks
+pub const SCHEDULE_VERSION: u32 = 2;
+
+/// Parses an ImportSchedule from JSON, migrating older versions to the current one.
+///
+/// Schedules without a version field are treated as version 1. Versions newer than
+/// SCHEDULE_VERSION are rejected with FileManagerError::UnsupportedVersion, and versions
+/// that aren't a non-negative integer, e.g. "2" or 2.0, with FileManagerError::InvalidVersion.
+///
+/// # Arguments
+///
+/// * 'json' - the schedule JSON
+pub fn parse_import_schedule(json: &str) -> Result<ImportSchedule, FileManagerError> {
+    let mut value: Value = serde_json::from_str(json)?;
+
+    let version = match value.get("version") {
+        None => 1,
+        Some(v) => v.as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or(FileManagerError::InvalidVersion(v.to_string()))?,
+    };
+
+    if version > SCHEDULE_VERSION {
+        return Err(FileManagerError::UnsupportedVersion(version));
+    }
+
+    if version < 2 {
+        value = migrate_v1_to_v2(value);
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Migrates a version 1 schedule to version 2.
+/// All additions in version 2 are optional, so only the version field needs to be set.
+///
+/// # Arguments
+///
+/// * 'value' - the version 1 schedule
+fn migrate_v1_to_v2(mut value: Value) -> Value {
+    if let Some(obj) = value.as_object_mut() {
+        obj.insert("version".to_string(), Value::from(2));
+    }
+
+    value
+}
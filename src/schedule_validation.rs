@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::Formatter;
+use std::ops::Add;
+use chrono::{DateTime, TimeDelta, Timelike, Utc};
+use crate::worker_common::{BlockType, ImportSchedule, BLOCK_UNIT_SIZE};
+
+/// A problem found in an import schedule, optionally tied to a block
+pub struct Finding {
+    pub block_id: Option<usize>,
+    pub message: String,
+}
+
+/// Implementation of the Display Trait for pretty print
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.block_id {
+            Some(block_id) => write!(f, "Block {:>3}: {}", block_id, self.message),
+            None => write!(f, "Schedule : {}", self.message),
+        }
+    }
+}
+
+/// Validates an import schedule before it is activated and returns all findings,
+/// an empty result means the schedule is valid.
+///
+/// Checks that there are blocks, that blocks are sorted, non-overlapping and aligned to BLOCK_UNIT_SIZE,
+/// that soc_in/soc_out are within 0-100 and consistent with the block type, that Discharge blocks
+/// have a discharge power, that no Unknown blocks are present and that block ids are unique.
+///
+/// # Arguments
+///
+/// * 'import_schedule' - the schedule to validate
+pub fn validate_import_schedule(import_schedule: &ImportSchedule) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = Vec::new();
+    let mut block_ids: HashSet<usize> = HashSet::new();
+    let mut finding = |block_id: Option<usize>, message: String| findings.push(Finding { block_id, message });
+
+    if import_schedule.blocks.is_empty() {
+        finding(None, "no blocks in schedule".to_string());
+    }
+
+    for (i, b) in import_schedule.blocks.iter().enumerate() {
+        let id = Some(b.block_id);
+
+        if !block_ids.insert(b.block_id) {
+            finding(id, "duplicate block id".to_string());
+        }
+        if b.block_type == BlockType::Unknown {
+            finding(id, "block type is Unknown".to_string());
+        }
+        if !is_aligned(b.start_time) {
+            finding(id, format!("start time {} not aligned to {} minutes", b.start_time, BLOCK_UNIT_SIZE));
+        }
+        if !is_aligned(b.end_time) {
+            finding(id, format!("end time {} not aligned to {} minutes", b.end_time, BLOCK_UNIT_SIZE));
+        }
+        if b.end_time < b.start_time {
+            finding(id, format!("end time {} before start time {}", b.end_time, b.start_time));
+        }
+
+        if let Some(prev) = i.checked_sub(1).map(|p| &import_schedule.blocks[p]) {
+            if b.start_time < prev.start_time {
+                finding(id, format!("not sorted, starts before block {}", prev.block_id));
+            } else if b.start_time < prev.end_time.add(TimeDelta::minutes(BLOCK_UNIT_SIZE)) {
+                finding(id, format!("overlaps block {}", prev.block_id));
+            }
+        }
+
+        if b.soc_in > 100 {
+            finding(id, format!("soc in {} out of range 0-100", b.soc_in));
+        }
+        if b.soc_out > 100 {
+            finding(id, format!("soc out {} out of range 0-100", b.soc_out));
+        }
+        match b.block_type {
+            BlockType::Charge if b.soc_out < b.soc_in => {
+                finding(id, format!("Charge block with soc out {} below soc in {}", b.soc_out, b.soc_in));
+            },
+            BlockType::Discharge if b.soc_out > b.soc_in => {
+                finding(id, format!("Discharge block with soc out {} above soc in {}", b.soc_out, b.soc_in));
+            },
+            _ => {},
+        }
+        if b.block_type == BlockType::Discharge && b.fd_pwr.is_none_or(|p| p <= 0.0) {
+            finding(id, "Discharge block without a positive discharge power (fd_pwr)".to_string());
+        }
+    }
+
+    findings
+}
+
+/// Checks whether a time is aligned to BLOCK_UNIT_SIZE
+///
+/// # Arguments
+///
+/// * 'date_time' - the time to check
+fn is_aligned(date_time: DateTime<Utc>) -> bool {
+    date_time.minute() as i64 % BLOCK_UNIT_SIZE == 0 && date_time.second() == 0 && date_time.nanosecond() == 0
+}
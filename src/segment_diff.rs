@@ -0,0 +1,111 @@
+use std::fmt;
+use std::fmt::Formatter;
+use foxess::Group;
+use crate::manager_inverter::copy_group;
+
+/// One time segment position compared between inverter and mygrid
+pub struct SegmentDiffRow {
+    pub index: usize,
+    pub current: Option<Group>,
+    pub intended: Option<Group>,
+    pub differs: bool,
+}
+
+/// Structured diff between the time segments set in the inverter and the ones
+/// mygrid intends to set
+pub struct SegmentDiff {
+    pub rows: Vec<SegmentDiffRow>,
+}
+
+impl SegmentDiff {
+    /// Compares time segments read back from the inverter with the intended ones position by position.
+    ///
+    /// Extra params are only compared for fields set in the intended segment, since the inverter
+    /// may report its own defaults for the others.
+    ///
+    /// # Arguments
+    ///
+    /// * 'current' - time segments as reported by the inverter
+    /// * 'intended' - time segments as created from the import schedule
+    pub fn new(current: &[Group], intended: &[Group]) -> Self {
+        let rows = (0..current.len().max(intended.len()))
+            .map(|i| {
+                let c = current.get(i);
+                let n = intended.get(i);
+                SegmentDiffRow {
+                    index: i,
+                    current: c.map(copy_group),
+                    intended: n.map(copy_group),
+                    differs: !matches!((c, n), (Some(c), Some(n)) if group_matches(c, n)),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Self { rows }
+    }
+
+    /// Returns true if inverter and mygrid time segments differ in any position
+    ///
+    pub fn has_changes(&self) -> bool {
+        self.rows.iter().any(|r| r.differs)
+    }
+}
+
+/// Implementation of the Display Trait for pretty print
+impl fmt::Display for SegmentDiff {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "   #  {:<34}  {:<34}", "Inverter", "MyGrid")?;
+        for r in &self.rows {
+            writeln!(f, "{} {:>2}: {:<34}  {:<34}",
+                     if r.differs { "*" } else { " " },
+                     r.index,
+                     r.current.as_ref().map_or("-".to_string(), format_group),
+                     r.intended.as_ref().map_or("-".to_string(), format_group))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks whether a time segment as reported by the inverter matches an intended time segment
+///
+/// # Arguments
+///
+/// * 'current' - time segment as reported by the inverter
+/// * 'intended' - time segment as created from the import schedule
+fn group_matches(current: &Group, intended: &Group) -> bool {
+    let param_matches = |c: Option<f64>, n: Option<f64>| n.is_none_or(|n| c.is_some_and(|c| (c - n).abs() < 0.5));
+
+    let extra_param_matches = match (&current.extra_param, &intended.extra_param) {
+        (_, None) => true,
+        (None, Some(n)) => n.fd_pwr.is_none() && n.min_soc_on_grid.is_none() && n.fd_soc.is_none() && n.max_soc.is_none()
+            && n.import_limit.is_none() && n.export_limit.is_none() && n.pv_limit.is_none() && n.reactive_power.is_none(),
+        (Some(c), Some(n)) => param_matches(c.fd_pwr, n.fd_pwr)
+            && param_matches(c.min_soc_on_grid, n.min_soc_on_grid)
+            && param_matches(c.fd_soc, n.fd_soc)
+            && param_matches(c.max_soc, n.max_soc)
+            && param_matches(c.import_limit, n.import_limit)
+            && param_matches(c.export_limit, n.export_limit)
+            && param_matches(c.pv_limit, n.pv_limit)
+            && param_matches(c.reactive_power, n.reactive_power),
+    };
+
+    current.start_hour == intended.start_hour && current.start_minute == intended.start_minute
+        && current.end_hour == intended.end_hour && current.end_minute == intended.end_minute
+        && current.work_mode == intended.work_mode
+        && extra_param_matches
+}
+
+/// Returns a fixed width textual representation of a time segment
+///
+/// # Arguments
+///
+/// * 'group' - the time segment to format
+pub fn format_group(group: &Group) -> String {
+    let soc = group.extra_param.as_ref().and_then(|e| e.fd_soc.or(e.max_soc));
+
+    format!("{:>02}:{:>02}-{:>02}:{:>02} {:<14} Soc {:>3}",
+            group.start_hour, group.start_minute, group.end_hour, group.end_minute,
+            group.work_mode.as_str(),
+            soc.map_or("-".to_string(), |s| format!("{}", s)))
+}
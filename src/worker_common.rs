@@ -2,16 +2,18 @@ use std::fmt;
 use std::fmt::Formatter;
 use std::ops::Add;
 use chrono::{DateTime, TimeDelta, Timelike, Utc};
-use foxess::FoxError;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use crate::{DEBUG_MODE, MANUAL_DAY};
-use crate::manager_files::{get_schedule_for_date, load_scheduled_blocks, FileManagerError};
-use crate::manual::ManualDaysError;
+use crate::DEBUG_MODE;
+use crate::config::SchedulePrecedence;
+use crate::manager_files::{get_schedule_for_date, load_scheduled_blocks, FileManagerError, Overlap};
+use crate::manager_inverter::errors::InverterError;
+use crate::manual::{manual_action, ManualAction, ManualDaysError};
 use crate::scheduler_common::SchedulingError;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ImportSchedule {
+    pub version: u32,
     pub blocks: Vec<Block>,
     pub schedule_id: i64,
 }
@@ -23,8 +25,11 @@ pub const BLOCK_UNIT_SIZE: i64 = 15;
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum BlockType {
     Charge,
+    Discharge,
     Hold,
     Use,
+    FeedIn,
+    PeakShaving,
     Unknown,
 }
 
@@ -32,10 +37,13 @@ pub enum BlockType {
 impl fmt::Display for BlockType {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            BlockType::Charge => write!(f, "Charge "),
-            BlockType::Hold   => write!(f, "Hold   "),
-            BlockType::Use    => write!(f, "Use    "),
-            BlockType::Unknown => write!(f, "Unknown"),
+            BlockType::Charge      => write!(f, "Charge     "),
+            BlockType::Discharge   => write!(f, "Discharge  "),
+            BlockType::Hold        => write!(f, "Hold       "),
+            BlockType::Use         => write!(f, "Use        "),
+            BlockType::FeedIn      => write!(f, "FeedIn     "),
+            BlockType::PeakShaving => write!(f, "PeakShaving"),
+            BlockType::Unknown     => write!(f, "Unknown    "),
         }
     }
 }
@@ -45,12 +53,15 @@ impl fmt::Display for BlockType {
 pub enum Status {
     Waiting,
     Started,
-    Full(FullAt),
+    Full(SocAt),
+    /// New in schedule version 2, consumers built for version 1 fail to deserialize it
+    Empty(SocAt),
     Error,
 }
 
+/// Soc reached by a block and when, as recorded with the Full and Empty status
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct FullAt {
+pub struct SocAt {
     pub time: DateTime<Utc>,
     pub soc: usize,
 }
@@ -61,7 +72,8 @@ impl fmt::Display for Status {
         match self {
             Status::Waiting => write!(f, "Waiting  "),
             Status::Started => write!(f, "Started  "),
-            Status::Full(full_at) => write!(f, "Full: {:>3} {:02}:{:02}", full_at.soc, full_at.time.hour(), full_at.time.minute()),
+            Status::Full(soc_at) => write!(f, "Full: {:>3} {:02}:{:02}", soc_at.soc, soc_at.time.hour(), soc_at.time.minute()),
+            Status::Empty(soc_at) => write!(f, "Empty: {:>3} {:02}:{:02}", soc_at.soc, soc_at.time.hour(), soc_at.time.minute()),
             Status::Error   => write!(f, "Error    "),
         }
     }
@@ -77,6 +89,11 @@ pub struct Block {
     pub true_soc_in: Option<usize>,
     pub soc_in: usize,
     pub soc_out: usize,
+    pub min_soc_on_grid: Option<f64>,
+    pub fd_pwr: Option<f64>,
+    pub import_limit: Option<f64>,
+    pub export_limit: Option<f64>,
+    pub pv_limit: Option<f64>,
     pub status: Status,
 }
 
@@ -97,24 +114,44 @@ impl fmt::Display for Block {
     }
 }
 
-/// Check if we are in debug mode or manual day
+/// Check if we are in debug mode or in a hands off manual window, i.e. no write operations to the inverter
 ///
-pub fn is_manual_debug() -> anyhow::Result<bool, WorkerError> {
+/// # Arguments
+///
+/// * 'date_time' - the instant to check
+pub fn is_hands_off(date_time: DateTime<Utc>) -> anyhow::Result<bool, WorkerError> {
     let debug_mode = *DEBUG_MODE
         .read()
         .map_err(|e| WorkerError::LockPoison(e.to_string()))?;
 
-    let manual_day = *MANUAL_DAY
+    Ok(debug_mode || manual_action(date_time)? == Some(ManualAction::HandsOff))
+}
+
+/// Check if we are in debug mode or in any manual window, i.e. the inverter isn't expected to follow the schedule
+///
+/// # Arguments
+///
+/// * 'date_time' - the instant to check
+pub fn is_manual_debug(date_time: DateTime<Utc>) -> anyhow::Result<bool, WorkerError> {
+    let debug_mode = *DEBUG_MODE
         .read()
         .map_err(|e| WorkerError::LockPoison(e.to_string()))?;
 
-    Ok(debug_mode || manual_day)
+    Ok(debug_mode || manual_action(date_time)?.is_some())
 }
 
-pub fn import_schedule_from_file(schedule_dir: &str, date_time: DateTime<Utc>) -> Result<Option<ImportSchedule>, WorkerError> {
+/// Loads the schedule including the given date time, schedule.json has precedence over dated
+/// schedule files. Any overlap among dated schedule files is returned alongside the schedule.
+///
+/// # Arguments
+///
+/// * 'schedule_dir' - the directory holding the schedule files
+/// * 'date_time' - the date time the schedule needs to include
+/// * 'precedence' - how to choose among overlapping dated schedule files
+pub fn import_schedule_from_file(schedule_dir: &str, date_time: DateTime<Utc>, precedence: SchedulePrecedence) -> Result<Option<(ImportSchedule, Option<Overlap>)>, WorkerError> {
     let loaded_schedule = match load_scheduled_blocks(schedule_dir, date_time)? {
-        Some(schedule) => Some(schedule),
-        None => get_schedule_for_date(schedule_dir, date_time)?,
+        Some(schedule) => Some((schedule, None)),
+        None => get_schedule_for_date(schedule_dir, date_time, precedence)?,
     };
 
     Ok(loaded_schedule)
@@ -127,7 +164,7 @@ pub enum WorkerError {
     #[error(transparent)]
     SkipDay(#[from] ManualDaysError),
     #[error(transparent)]
-    FoxESS(#[from] FoxError),
+    Inverter(#[from] InverterError),
     #[error(transparent)]
     Scheduling(#[from] SchedulingError),
     #[error("lock poison error: {0}")]